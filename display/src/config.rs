@@ -8,8 +8,42 @@ pub struct Endpoint {
     #[serde(deserialize_with = "deserialize_uri")]
     /// In the format "h2c://...:..." for gRPC.
     pub uri: tonic::transport::Uri,
+    /// Optional if the exporter accepts `client_certificate_file` instead.
+    #[serde(default)]
     #[serde(deserialize_with = "deserialize_secstr")]
-    pub password: secstr::SecStr,
+    pub password: Option<secstr::SecStr>,
+    /// Send the password itself to exporters that don't support challenge-response authentication, rather
+    /// than refusing to connect. Only for use while upgrading exporters, since anyone watching the connection
+    /// can read the password.
+    #[serde(default)]
+    pub allow_plaintext_password: bool,
+    /// If there's no `password`, pair with the exporter to get one, saved in `Config::credentials_directory`.
+    #[serde(default)]
+    pub pair: bool,
+    /// Issued by `exporter issue-token`, instead of a `password`.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_secstr")]
+    pub token: Option<secstr::SecStr>,
+
+    /// A PEM file of CA certificates to trust for this endpoint instead of the public CAs, e.g. for an exporter
+    /// with a certificate from a private CA.
+    #[serde(default)]
+    pub ca_certificates_file: Option<std::path::PathBuf>,
+    /// The SHA-256 fingerprint of the exporter's certificate, as printed by
+    /// `openssl x509 -noout -fingerprint -sha256`. If set, only that certificate is accepted, and nothing else
+    /// about it (e.g. its issuer or expiry) is checked. Useful for self-signed certificates.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_fingerprint")]
+    pub pinned_certificate_sha256: Option<String>,
+    /// The name to send in SNI and check the certificate against, if it's different from the host in `uri`, e.g.
+    /// when connecting by IP address.
+    #[serde(default)]
+    pub tls_domain_name: Option<String>,
+    /// A PEM file of a certificate chain (and its key) to identify the display to the exporter with.
+    #[serde(default)]
+    pub client_certificate_file: Option<std::path::PathBuf>,
+    #[serde(default)]
+    pub client_key_file: Option<std::path::PathBuf>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -21,12 +55,27 @@ pub struct Config {
     #[serde(deserialize_with = "deserialize_seconds")]
     /// In the config, this is provided as `poll_interval_seconds: u64`, but at runtime
     /// it's converted directly into a `Duration` for ease of use.
+    /// Used for exporters that don't support streaming updates, and as the delay before reconnecting.
     pub poll_interval: Duration,
 
     // The largest message that can be received from an exporter. Essentially, the limit
     // on photo size.
     #[serde(default = "default_max_received_message_size")]
     pub max_received_message_size: usize,
+
+    /// Where to keep photos downloaded from exporters between restarts. If unset, photos are only
+    /// cached in memory.
+    #[serde(default)]
+    pub photo_cache_directory: Option<std::path::PathBuf>,
+
+    /// Where to save passwords from pairing with exporters. Needed by endpoints with `pair` set.
+    #[serde(default)]
+    pub credentials_directory: Option<std::path::PathBuf>,
+
+    /// Encodings ("gzip" or "zstd") that exporters may compress responses with, in order of preference.
+    #[serde(default = "default_compression")]
+    #[serde(deserialize_with = "deserialize_compression")]
+    pub compression: Vec<tonic::codec::CompressionEncoding>,
 }
 impl ConfigParamFromEnv for Config {
     fn parse(val: &str) -> Result<Self, String>
@@ -43,6 +92,12 @@ const fn default_poll_interval() -> Duration {
 const fn default_max_received_message_size() -> usize {
     30 * 1024 * 1024 // 30 MiB
 }
+fn default_compression() -> Vec<tonic::codec::CompressionEncoding> {
+    vec![
+        tonic::codec::CompressionEncoding::Zstd,
+        tonic::codec::CompressionEncoding::Gzip,
+    ]
+}
 
 fn deserialize_uri<'de, D>(deserializer: D) -> Result<tonic::transport::Uri, D::Error>
 where
@@ -51,13 +106,25 @@ where
     tonic::transport::Uri::from_str(Deserialize::deserialize(deserializer)?)
         .map_err(|e| serde::de::Error::custom(e.to_string()))
 }
-fn deserialize_secstr<'de, D>(deserializer: D) -> Result<secstr::SecStr, D::Error>
+fn deserialize_secstr<'de, D>(deserializer: D) -> Result<Option<secstr::SecStr>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
+    let password: Option<String> = Deserialize::deserialize(deserializer)?;
     // Bit weird, to work around the Infallible error.
-    let Ok(result) = secstr::SecStr::from_str(Deserialize::deserialize(deserializer)?);
-    Ok(result)
+    Ok(password.map(|p| {
+        let Ok(result) = secstr::SecStr::from_str(&p);
+        result
+    }))
+}
+fn deserialize_fingerprint<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let fingerprint: Option<String> = Deserialize::deserialize(deserializer)?;
+    fingerprint
+        .map(|f| lib::tls::parse_fingerprint(&f).map_err(serde::de::Error::custom))
+        .transpose()
 }
 fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
@@ -66,3 +133,15 @@ where
     // Bit weird, to work around the Infallible error.
     Ok(Duration::from_secs(Deserialize::deserialize(deserializer)?))
 }
+fn deserialize_compression<'de, D>(
+    deserializer: D,
+) -> Result<Vec<tonic::codec::CompressionEncoding>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+    names
+        .iter()
+        .map(|n| ConfigParamFromEnv::parse(n).map_err(serde::de::Error::custom))
+        .collect()
+}
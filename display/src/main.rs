@@ -6,20 +6,31 @@ use snapshot_manager::{EndpointSnapshots, SnapshotManager};
 use std::time::Duration;
 
 use sdl2::render::Canvas;
+use sdl2::ttf::Font;
 
 use lib::env_params::get_env_variable;
 
 mod config;
+mod photo_cache;
 mod snapshot_manager;
 mod tile;
-use tile::{snapshots_to_tiles, Tile};
+mod tls;
+use tile::{grid_layout, snapshots_to_tiles, tile_size, Tile};
 
-fn draw_frame(snapshots: &EndpointSnapshots, canvas: &mut Canvas<Window>) -> Result<(), String> {
+/// Bundled so the display doesn't depend on whatever fonts happen to be installed. See `assets/` for the license.
+const FONT_DATA: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+const FONT_POINT_SIZE: u16 = 28;
+
+fn draw_frame(
+    snapshots: &EndpointSnapshots,
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+) -> Result<(), String> {
     let texture_creator = canvas.texture_creator();
     canvas.set_draw_color(sdl2::pixels::Color::BLACK);
     canvas.clear();
 
-    let tiles = snapshots_to_tiles(&texture_creator, snapshots);
+    let tiles = snapshots_to_tiles(&texture_creator, font, snapshots);
     draw_tiles(&tiles, canvas)?;
 
     canvas.present();
@@ -27,17 +38,8 @@ fn draw_frame(snapshots: &EndpointSnapshots, canvas: &mut Canvas<Window>) -> Res
 }
 
 fn draw_tiles(tiles: &Vec<Tile>, canvas: &mut Canvas<Window>) -> Result<(), String> {
-    // Make a square grid of tiles. `grid_size` is how many rows/columns we have.
-    let grid_size = ((tiles.len() as f32).sqrt().ceil() as u32).max(1);
-
-    let num_columns = grid_size;
-    // Sneaky trick: with e.g. 2 tiles, we don't want 2 rows and columns, it wastes half the screen.
-    // Instead, pick the number of columns according to a square, but only use as many rows as
-    // necessary to fit all the tiles with that many columns.
-    let num_rows = ((tiles.len() as f32 / num_columns as f32).ceil() as u32).max(1);
-
-    let (output_width, output_height) = canvas.output_size()?;
-    let (tile_width, tile_height) = (output_width / num_columns, output_height / num_rows);
+    let (num_columns, _) = grid_layout(tiles.len());
+    let (tile_width, tile_height) = tile_size(canvas.output_size()?, tiles.len());
     for (i, tile) in tiles.iter().enumerate() {
         let row = i as u32 / num_columns;
         let column = i as u32 % num_columns;
@@ -57,6 +59,7 @@ fn draw_tiles(tiles: &Vec<Tile>, canvas: &mut Canvas<Window>) -> Result<(), Stri
 
 fn main_loop(
     canvas: &mut Canvas<Window>,
+    font: &Font,
     event_pump: &mut sdl2::EventPump,
     snapshot_receiver: std::sync::mpsc::Receiver<EndpointSnapshots>,
 ) {
@@ -82,7 +85,7 @@ fn main_loop(
         if let Some(snapshots) = snapshot_receiver.try_iter().last() {
             latest_snapshots = snapshots;
         }
-        if let Err(e) = draw_frame(&latest_snapshots, canvas) {
+        if let Err(e) = draw_frame(&latest_snapshots, canvas, font) {
             log::error!("{}", e);
         }
         std::thread::sleep(Duration::from_millis(200)); // 5fps, don't need anything fancy
@@ -114,6 +117,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .expect("failed to build window");
     sdl_context.mouse().show_cursor(false);
 
+    let ttf_context = sdl2::ttf::init().expect("failed to init SDL_ttf");
+    let font = ttf_context
+        .load_font_from_rwops(
+            sdl2::rwops::RWops::from_bytes(FONT_DATA).expect("failed to read font"),
+            FONT_POINT_SIZE,
+        )
+        .expect("failed to load font");
+
     // Try to use smooth texture scaling.
     if !sdl2::hint::set("SDL_HINT_RENDER_SCALE_QUALITY", "linear") {
         log::error!("Failed to set render scale quality hint.");
@@ -125,13 +136,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build()
         .expect("failed to build window's canvas");
 
-    let (snapshot_manager, snapshot_receiver) = SnapshotManager::initialise(config).await;
+    let screen_size = canvas.output_size().expect("failed to get screen size");
+    let (snapshot_manager, snapshot_receiver) =
+        SnapshotManager::initialise(config, screen_size).await;
 
-    // Start periodically fetching locations in the background.
+    // Start receiving location updates in the background.
     let snapshot_manager_handle = tokio::spawn(snapshot_manager.start_loop());
 
     let mut event_pump = sdl_context.event_pump().unwrap();
-    main_loop(&mut canvas, &mut event_pump, snapshot_receiver);
+    main_loop(&mut canvas, &font, &mut event_pump, snapshot_receiver);
 
     snapshot_manager_handle.abort();
 
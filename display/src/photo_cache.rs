@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Photos that haven't been used for this long are dropped from memory. They'll be read from disk if they're
+/// needed again.
+const MEMORY_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Photos that haven't been used for this long are deleted from disk.
+const DISK_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Photos received from exporters, keyed by their digest. Photos are kept in memory, and optionally
+/// also on disk so they don't need to be downloaded again after a restart.
+pub struct PhotoCache {
+    directory: Option<PathBuf>,
+    /// Each photo with when it was last used.
+    photos: HashMap<String, (Instant, Vec<u8>)>,
+}
+impl PhotoCache {
+    pub fn new(directory: Option<PathBuf>) -> Self {
+        if let Some(d) = &directory {
+            if let Err(e) = std::fs::create_dir_all(d) {
+                log::warn!("Failed to create photo cache directory {d:?}: {e}");
+            }
+        }
+        PhotoCache {
+            directory,
+            photos: HashMap::new(),
+        }
+    }
+
+    fn path(&self, digest: &str) -> Option<PathBuf> {
+        // Digests come from the exporter, so check they're safe to use as a filename.
+        if !lib::photo::is_valid_digest(digest) {
+            return None;
+        }
+        self.directory.as_ref().map(|d| d.join(digest))
+    }
+
+    pub fn get(&mut self, digest: &str) -> Option<Vec<u8>> {
+        if let Some((last_used, data)) = self.photos.get_mut(digest) {
+            *last_used = Instant::now();
+            return Some(data.clone());
+        }
+
+        let path = self.path(digest)?;
+        let data = std::fs::read(&path).ok()?;
+        if lib::photo::digest(&data) != digest {
+            log::warn!("Cached photo {path:?} is corrupt, ignoring it");
+            return None;
+        }
+        mark_used(&path);
+        self.remember(digest.to_string(), data.clone());
+        Some(data)
+    }
+
+    pub fn insert(&mut self, digest: String, data: Vec<u8>) {
+        if let Some(path) = self.path(&digest) {
+            if let Err(e) = std::fs::write(&path, &data) {
+                log::warn!("Failed to write photo to cache at {path:?}: {e}");
+            }
+            self.remove_unused_from_disk();
+        }
+        self.remember(digest, data);
+    }
+
+    fn remember(&mut self, digest: String, data: Vec<u8>) {
+        self.photos
+            .retain(|_, (last_used, _)| last_used.elapsed() < MEMORY_LIFETIME);
+        self.photos.insert(digest, (Instant::now(), data));
+    }
+
+    /// A file's modification time is when the photo was last used.
+    fn remove_unused_from_disk(&self) {
+        let Some(directory) = &self.directory else {
+            return;
+        };
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Failed to list photo cache directory {directory:?}: {e}");
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            // Leave alone anything that isn't a cached photo.
+            if !lib::photo::is_valid_digest(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            let unused = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > DISK_LIFETIME);
+            if unused {
+                log::info!("Removing unused photo {:?} from the cache", entry.path());
+                if let Err(e) = std::fs::remove_file(entry.path()) {
+                    log::warn!(
+                        "Failed to remove {:?} from the photo cache: {e}",
+                        entry.path()
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn mark_used(path: &Path) {
+    let result = std::fs::File::options()
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = result {
+        log::warn!("Failed to update the modification time of cached photo {path:?}: {e}");
+    }
+}
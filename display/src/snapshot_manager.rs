@@ -1,15 +1,45 @@
-use std::sync::mpsc;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use lib::clock_pb;
+use lib::clock_pb::auth_service_client::AuthServiceClient;
 use lib::clock_pb::clock_service_client::ClockServiceClient;
-use lib::clock_pb::{GetPeopleLocationsRequest, GetPeopleLocationsResponse};
+use lib::clock_pb::pairing_service_client::PairingServiceClient;
+use lib::clock_pb::{
+    Dimensions, Feature, FinishPairingRequest, GetChallengeRequest, GetExporterInfoRequest,
+    GetExporterInfoResponse, GetPeopleLocationsRequest, GetPeopleLocationsResponse,
+    GetPhotoRequest, StartPairingRequest,
+};
 use lib::password::AddPassword;
+use secstr::SecStr;
 
-use crate::config::Config;
+use crate::config::{Config, Endpoint};
+use crate::photo_cache::PhotoCache;
+use crate::tile;
+use crate::tls;
 
+#[derive(Clone)]
 pub struct Snapshot {
     pub people: Vec<clock_pb::Person>,
     pub zones: std::collections::HashMap<String, clock_pb::Zone>,
+    pub errors: Vec<clock_pb::EntityError>,
+    /// Set if the exporter couldn't reach Home Assistant, to when the locations were last read from it.
+    pub stale_since: Option<SystemTime>,
+    /// Shown instead of people while waiting for the exporter's owner to approve pairing.
+    pub pairing_code: Option<String>,
+}
+impl Snapshot {
+    fn pairing(code: String) -> Self {
+        Snapshot {
+            people: vec![],
+            zones: Default::default(),
+            errors: vec![],
+            stale_since: None,
+            pairing_code: Some(code),
+        }
+    }
 }
 impl From<GetPeopleLocationsResponse> for Snapshot {
     fn from(response: GetPeopleLocationsResponse) -> Self {
@@ -20,61 +50,473 @@ impl From<GetPeopleLocationsResponse> for Snapshot {
                 .into_iter()
                 .map(|z| (z.id.clone(), z))
                 .collect(),
+            errors: response.errors,
+            stale_since: response
+                .stale_age_seconds
+                .map(|age| SystemTime::now() - Duration::from_secs(age.into())),
+            pairing_code: None,
         }
     }
 }
 pub type EndpointSnapshots = Vec<Snapshot>;
 
+/// A new snapshot from the endpoint at the given index in the config.
+type EndpointUpdate = (usize, Snapshot);
+
+type Client = ClockServiceClient<
+    tonic::service::interceptor::InterceptedService<tonic::transport::Channel, AddPassword>,
+>;
+
+/// Fetch a new challenge this long before the current one expires, so requests in flight don't fail.
+const CHALLENGE_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// How often to check whether the exporter's owner has approved pairing.
+const PAIRING_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often to ping an exporter to check that a quiet `WatchPeopleLocations` stream is still alive.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct SnapshotManager {
     config: Config,
+    /// (width, height) of the screen, so exporters can send appropriately-sized photos.
+    screen_size: (u32, u32),
     tx: mpsc::Sender<EndpointSnapshots>,
 }
 impl SnapshotManager {
-    pub async fn initialise(config: Config) -> (Self, mpsc::Receiver<EndpointSnapshots>) {
+    pub async fn initialise(
+        config: Config,
+        screen_size: (u32, u32),
+    ) -> (Self, mpsc::Receiver<EndpointSnapshots>) {
         let (tx, rx) = mpsc::channel();
-        let snapshot_manager = SnapshotManager { config, tx };
+        let snapshot_manager = SnapshotManager {
+            config,
+            screen_size,
+            tx,
+        };
         (snapshot_manager, rx)
     }
 
-    pub async fn start_loop(mut self) {
+    pub async fn start_loop(self) {
+        let config = Arc::new(self.config);
+        let photo_cache = Arc::new(Mutex::new(PhotoCache::new(
+            config.photo_cache_directory.clone(),
+        )));
+        let (update_tx, mut update_rx) = tokio::sync::mpsc::channel(config.endpoints.len().max(1));
+        let (tile_count_tx, tile_count) = tokio::sync::watch::channel(0);
+
+        // Each endpoint is followed independently, so a slow or broken exporter doesn't hold up the others.
+        // Dropping the set aborts the tasks.
+        let mut endpoint_tasks = tokio::task::JoinSet::new();
+        for index in 0..config.endpoints.len() {
+            let follower = EndpointFollower {
+                config: config.clone(),
+                photo_cache: photo_cache.clone(),
+                index,
+                updates: update_tx.clone(),
+                last_version: None,
+                screen_size: self.screen_size,
+                tile_count: tile_count.clone(),
+            };
+            endpoint_tasks.spawn(follower.run());
+        }
+
+        let mut latest_snapshots: Vec<Option<Snapshot>> = vec![None; config.endpoints.len()];
+        while let Some((index, snapshot)) = update_rx.recv().await {
+            latest_snapshots[index] = Some(snapshot);
+            let snapshots = latest_snapshots.iter().flatten().cloned().collect();
+            tile_count_tx.send_replace(tile::count_tiles(&snapshots));
+            if self.tx.send(snapshots).is_err() {
+                // Other end of the pipe has already closed, just terminate.
+                break;
+            }
+        }
+    }
+}
+
+/// Follows a single exporter, passing its snapshots on to the `SnapshotManager`.
+struct EndpointFollower {
+    config: Arc<Config>,
+    photo_cache: Arc<Mutex<PhotoCache>>,
+    /// Which of `config.endpoints` to follow.
+    index: usize,
+    updates: tokio::sync::mpsc::Sender<EndpointUpdate>,
+    /// The version of the last snapshot sent on, so the exporter can avoid resending it.
+    last_version: Option<String>,
+    screen_size: (u32, u32),
+    /// How many tiles are currently on screen across all endpoints, to work out how big photos need to be.
+    tile_count: tokio::sync::watch::Receiver<usize>,
+}
+impl EndpointFollower {
+    fn endpoint(&self) -> &Endpoint {
+        &self.config.endpoints[self.index]
+    }
+
+    async fn run(mut self) {
         loop {
             match self.update_snapshots().await {
                 // Other end of the pipe has already closed, just terminate.
                 Ok(true) => break,
-                Err(e) => log::error!("{}", e),
+                Err(e) => log::error!("{}: {}", self.endpoint().uri, e),
                 _ => (),
             }
             tokio::time::sleep(self.config.poll_interval).await;
         }
     }
 
+    /// Receive snapshots from the endpoint until the connection fails. Returns whether the receiver has hung up.
     async fn update_snapshots(&mut self) -> Result<bool, String> {
-        let mut snapshots = vec![];
-        for endpoint in &self.config.endpoints {
-            log::info!("Connecting to {}", endpoint.uri);
-            let channel = tonic::transport::Channel::builder(endpoint.uri.clone())
-                .connect()
+        let endpoint = self.endpoint();
+        log::info!("Connecting to {}", endpoint.uri);
+        let channel = tls::connect(endpoint, |builder| {
+            builder
+                .http2_keep_alive_interval(KEEP_ALIVE_INTERVAL)
+                .keep_alive_while_idle(true)
+        })
+        .await?;
+        log::info!("Connected to {}", endpoint.uri);
+
+        let password = match (&endpoint.password, &endpoint.token) {
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "{} has both a password and a token, only one can be used",
+                    endpoint.uri
+                ))
+            }
+            (None, Some(token)) => AddPassword::with_token(token)?,
+            (Some(password), None) => AddPassword::new(password.clone()),
+            (None, None) if endpoint.pair => {
+                AddPassword::new(self.paired_password(channel.clone()).await?)
+            }
+            (None, None) => AddPassword::without_password(),
+        };
+        let mut auth = Auth {
+            client: AuthServiceClient::new(channel.clone()),
+            password,
+        };
+        auth.refresh(endpoint).await?;
+        let mut client = ClockServiceClient::with_interceptor(channel, auth.password.clone());
+        // Allow receiving larger images than the tonic default 4MiB.
+        client = client.max_decoding_message_size(self.config.max_received_message_size);
+        // Requests are tiny, so only responses are compressed.
+        for encoding in &self.config.compression {
+            client = client.accept_compressed(*encoding);
+        }
+
+        let info = Self::get_exporter_info(&mut client, endpoint).await?;
+        let features: Vec<Feature> = info.features().collect();
+        let photo_digests_only = features.contains(&Feature::PhotoDigests);
+
+        if features.contains(&Feature::Streaming) {
+            let mut tile_count = self.tile_count.clone();
+            loop {
+                // The tile size can only be sent when the stream starts, so the stream is restarted whenever it
+                // changes, e.g. once every endpoint's first snapshot has arrived.
+                let requested_tile_size = self.tile_size();
+                let request = self.make_request(photo_digests_only);
+                let mut stream = client
+                    .watch_people_locations(request)
+                    .await
+                    .map_err(|s| format!("Bad response from server: {s}"))?
+                    .into_inner();
+                loop {
+                    let response = tokio::select! {
+                        response = stream.message() => response
+                            .map_err(|s| format!("Bad response from server: {s}"))?
+                            .ok_or("Server closed the stream")?,
+                        changed = tile_count.changed() => {
+                            if changed.is_err() {
+                                // The `SnapshotManager` has stopped.
+                                return Ok(true);
+                            }
+                            if self.tile_size() != requested_tile_size {
+                                log::info!("{}: tile size changed, restarting stream", self.endpoint().uri);
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    log::trace!("Got streamed response: {response:?}");
+                    if self
+                        .handle_response(&mut client, &mut auth, response)
+                        .await?
+                    {
+                        return Ok(true);
+                    }
+                }
+                auth.refresh(self.endpoint()).await?;
+            }
+        } else {
+            loop {
+                auth.refresh(self.endpoint()).await?;
+                let request = self.make_request(photo_digests_only);
+                let response = client
+                    .get_people_locations(request)
+                    .await
+                    .map_err(|s| format!("Bad response from server: {s}"))?
+                    .into_inner();
+
+                log::trace!("Got response: {response:?}");
+                if self
+                    .handle_response(&mut client, &mut auth, response)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                tokio::time::sleep(self.config.poll_interval).await;
+            }
+        }
+    }
+
+    /// The password saved from pairing with the exporter, or a new one from pairing now.
+    async fn paired_password(&self, channel: tonic::transport::Channel) -> Result<SecStr, String> {
+        let endpoint = self.endpoint();
+        let directory = self
+            .config
+            .credentials_directory
+            .as_ref()
+            .ok_or("Pairing needs credentials_directory to be set")?;
+        // Named by the exporter's URI, so that each exporter gets its own password.
+        let path = directory.join(format!(
+            "{}.password",
+            lib::photo::digest(endpoint.uri.to_string().as_bytes())
+        ));
+        match std::fs::read(&path) {
+            Ok(password) => return Ok(SecStr::new(password)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        }
+
+        let password = self.pair(channel).await?;
+        std::fs::create_dir_all(directory)
+            .and_then(|_| {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&path)
+            })
+            .and_then(|mut file| file.write_all(password.unsecure()))
+            .map_err(|e| format!("Failed to save password to {}: {e}", path.display()))?;
+        log::info!("Paired with {}", endpoint.uri);
+        Ok(password)
+    }
+
+    /// Show a code on screen until the exporter's owner approves it with `exporter pair`.
+    async fn pair(&self, channel: tonic::transport::Channel) -> Result<SecStr, String> {
+        let mut client = PairingServiceClient::new(channel);
+        let keys = lib::pairing::KeyPair::generate()?;
+        let display_public_key = keys.public_key.clone();
+        let started = match client
+            .start_pairing(StartPairingRequest {
+                display_key_commitment: lib::pairing::commitment(&display_public_key),
+            })
+            .await
+        {
+            Ok(rpc) => rpc.into_inner(),
+            Err(s) if s.code() == tonic::Code::Unimplemented => {
+                return Err(
+                    "Exporter doesn't support pairing, or doesn't have PAIRING_DIRECTORY set"
+                        .to_string(),
+                )
+            }
+            Err(s) => return Err(format!("Failed to start pairing: {s}")),
+        };
+        let code = lib::pairing::code(&display_public_key, &started.exporter_public_key);
+        let password = keys.agree(&display_public_key, &started.exporter_public_key)?;
+
+        log::info!(
+            "{}: waiting for pairing code {code} to be approved",
+            self.endpoint().uri
+        );
+        // If the receiver has hung up, this task is about to be aborted anyway.
+        let _ = self
+            .updates
+            .send((self.index, Snapshot::pairing(code)))
+            .await;
+        let deadline =
+            tokio::time::Instant::now() + Duration::from_secs(started.lifetime_seconds.into());
+        loop {
+            let response = client
+                .finish_pairing(FinishPairingRequest {
+                    pairing_id: started.pairing_id.clone(),
+                    display_public_key: display_public_key.clone(),
+                })
                 .await
-                .map_err(|e| format!("Failed to connect: {e}"))?;
-            log::info!("Connected");
+                .map_err(|s| format!("Failed to finish pairing: {s}"))?
+                .into_inner();
+            if response.approved {
+                return Ok(password);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err("Pairing wasn't approved in time".to_string());
+            }
+            tokio::time::sleep(PAIRING_POLL_INTERVAL).await;
+        }
+    }
+
+    fn tile_size(&self) -> (u32, u32) {
+        tile::tile_size(self.screen_size, *self.tile_count.borrow())
+    }
 
-            let mut client = ClockServiceClient::with_interceptor(
-                channel,
-                AddPassword::new(endpoint.password.clone()),
+    fn make_request(&self, photo_digests_only: bool) -> GetPeopleLocationsRequest {
+        let to_dimensions = |(width, height)| Dimensions { width, height };
+        let tile_size = self.tile_size();
+        GetPeopleLocationsRequest {
+            photo_digests_only,
+            last_version: self.last_version.clone(),
+            screen_size: Some(to_dimensions(self.screen_size)),
+            tile_size: Some(to_dimensions(tile_size)),
+        }
+    }
+
+    /// Pass a response on to the `SnapshotManager`, unless it's unchanged. Returns whether the receiver has hung up.
+    async fn handle_response(
+        &mut self,
+        client: &mut Client,
+        auth: &mut Auth,
+        mut response: GetPeopleLocationsResponse,
+    ) -> Result<bool, String> {
+        if response.unchanged {
+            log::debug!("{}: nothing has changed", self.endpoint().uri);
+            return Ok(false);
+        }
+        if let Some(age) = response.stale_age_seconds {
+            log::warn!(
+                "{}: the exporter can't reach Home Assistant, showing locations from {age}s ago",
+                self.endpoint().uri
             );
-            // Allow receiving larger images than the tonic default 4MiB.
-            client = client.max_decoding_message_size(self.config.max_received_message_size);
+        }
+        // Streamed responses can arrive long after the challenge was fetched.
+        auth.refresh(self.endpoint()).await?;
+        let has_all_photos = self.resolve_photos(client, &mut response).await;
+        // If any photos are missing, ask for the whole response again next time so they're retried.
+        self.last_version = has_all_photos.then(|| response.version.clone()).flatten();
 
-            let rpc = client
-                .get_people_locations(GetPeopleLocationsRequest {})
-                .await
-                .map_err(|s| format!("Bad response from server: {s}"))?;
-            let response = rpc.into_inner();
+        Ok(self
+            .updates
+            .send((self.index, response.into()))
+            .await
+            .is_err())
+    }
+
+    async fn get_exporter_info(
+        client: &mut Client,
+        endpoint: &Endpoint,
+    ) -> Result<GetExporterInfoResponse, String> {
+        let info = match client.get_exporter_info(GetExporterInfoRequest {}).await {
+            Ok(rpc) => rpc.into_inner(),
+            Err(s) if s.code() == tonic::Code::Unimplemented => {
+                log::info!(
+                    "{} predates GetExporterInfo, assuming no optional features",
+                    endpoint.uri
+                );
+                return Ok(GetExporterInfoResponse::default());
+            }
+            Err(s) => return Err(format!("Bad response from server: {s}")),
+        };
 
-            log::trace!("Got response: {response:?}");
-            snapshots.push(response.into());
+        log::info!(
+            "{} is {}: version {}, protocol revision {}, features {:?}",
+            endpoint.uri,
+            info.household_name
+                .as_deref()
+                .unwrap_or("an unnamed exporter"),
+            info.exporter_version,
+            info.protocol_revision,
+            info.features().collect::<Vec<_>>(),
+        );
+        Ok(info)
+    }
+
+    /// Fill in `photo_data` for any photos that were only sent as a digest, from the cache if possible.
+    /// Photos that can't be fetched are left empty rather than failing the whole update. Returns whether all
+    /// photos were resolved.
+    async fn resolve_photos(
+        &self,
+        client: &mut Client,
+        response: &mut GetPeopleLocationsResponse,
+    ) -> bool {
+        let photos = response
+            .people
+            .iter_mut()
+            .map(|p| (&p.photo_digest, &mut p.photo_data))
+            .chain(
+                response
+                    .zones
+                    .iter_mut()
+                    .map(|z| (&z.photo_digest, &mut z.photo_data)),
+            );
+        let mut has_all_photos = true;
+        for (digest, photo_data) in photos {
+            let (Some(digest), None) = (digest, &photo_data) else {
+                continue;
+            };
+            match self.get_photo(client, digest).await {
+                Ok(data) => *photo_data = Some(data),
+                Err(e) => {
+                    log::error!("Failed to fetch photo {digest}: {e}");
+                    has_all_photos = false;
+                }
+            }
+        }
+        has_all_photos
+    }
+
+    async fn get_photo(&self, client: &mut Client, digest: &str) -> Result<Vec<u8>, String> {
+        if let Some(data) = self.photo_cache.lock().unwrap().get(digest) {
+            return Ok(data);
+        }
+
+        log::info!("Downloading photo {digest}");
+        let data = client
+            .get_photo(GetPhotoRequest {
+                digest: digest.to_string(),
+            })
+            .await
+            .map_err(|s| format!("Bad response from server: {s}"))?
+            .into_inner()
+            .photo_data;
+        if lib::photo::digest(&data) != digest {
+            return Err("Photo doesn't match its digest".to_string());
+        }
+
+        self.photo_cache
+            .lock()
+            .unwrap()
+            .insert(digest.to_string(), data.clone());
+        Ok(data)
+    }
+}
+
+/// Keeps the challenge used to authenticate to an exporter fresh.
+struct Auth {
+    client: AuthServiceClient<tonic::transport::Channel>,
+    password: AddPassword,
+}
+impl Auth {
+    async fn refresh(&mut self, endpoint: &Endpoint) -> Result<(), String> {
+        if !self.password.needs_challenge(CHALLENGE_REFRESH_MARGIN) {
+            return Ok(());
+        }
+        match self.client.get_challenge(GetChallengeRequest {}).await {
+            Ok(rpc) => {
+                self.password.set_challenge(rpc.into_inner()).await;
+                Ok(())
+            }
+            Err(s) if s.code() == tonic::Code::Unimplemented && endpoint.allow_plaintext_password => {
+                log::warn!(
+                    "{} predates challenge-response authentication, sending the password in plaintext",
+                    endpoint.uri
+                );
+                self.password.use_plaintext();
+                Ok(())
+            }
+            Err(s) if s.code() == tonic::Code::Unimplemented => Err(
+                "Exporter predates challenge-response authentication, set allow_plaintext_password to connect anyway"
+                    .to_string(),
+            ),
+            Err(s) => Err(format!("Failed to get a challenge: {s}")),
         }
-        let has_hung_up = self.tx.send(snapshots).is_err();
-        Ok(has_hung_up)
     }
 }
@@ -1,8 +1,12 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use crate::snapshot_manager::{EndpointSnapshots, Snapshot};
 use lib::clock_pb;
 use sdl2::image::LoadTexture;
+use sdl2::pixels::Color;
 use sdl2::rect::Rect;
-use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
+use sdl2::ttf::Font;
 
 fn bytes_to_texture<'a, T>(
     texture_creator: &'a TextureCreator<T>,
@@ -11,6 +15,76 @@ fn bytes_to_texture<'a, T>(
     texture_creator.load_texture_bytes(bytes)
 }
 
+fn text_to_texture<'a, T>(
+    texture_creator: &'a TextureCreator<T>,
+    font: &Font,
+    text: &str,
+) -> Result<Texture<'a>, String> {
+    let surface = font
+        .render(text)
+        .blended(Color::WHITE)
+        .map_err(|e| e.to_string())?;
+    texture_creator
+        .create_texture_from_surface(&surface)
+        .map_err(|e| e.to_string())
+}
+
+/// A short approximate duration like "3h", for captions.
+fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes >= 60 * 24 {
+        format!("{}d", minutes / (60 * 24))
+    } else if minutes >= 60 {
+        format!("{}h", minutes / 60)
+    } else if minutes >= 1 {
+        format!("{minutes}m")
+    } else {
+        "<1m".to_string()
+    }
+}
+
+/// E.g. "Adam — at Work for 3h", or "Adam — Work" if the exporter didn't say how long they've been there.
+/// Falls back to the entity ID if the exporter didn't provide a name. If the locations are stale, says how
+/// old they are, e.g. "Adam — at Work for 3h (as of 5m ago)".
+fn make_caption(
+    person: &clock_pb::Person,
+    zone: Option<&clock_pb::Zone>,
+    stale_since: Option<SystemTime>,
+) -> String {
+    let caption = make_location_caption(person, zone);
+    match stale_since {
+        Some(since) => format!(
+            "{caption} (as of {} ago)",
+            format_duration(SystemTime::now().duration_since(since).unwrap_or_default())
+        ),
+        None => caption,
+    }
+}
+
+fn make_location_caption(person: &clock_pb::Person, zone: Option<&clock_pb::Zone>) -> String {
+    let name = person.name.as_ref().unwrap_or(&person.id);
+    let Some(zone_name) = zone
+        .and_then(|z| z.name.as_ref())
+        .or(person.zone_name.as_ref())
+    else {
+        return name.clone();
+    };
+    let Some(since) = person.in_zone_since_unix_seconds else {
+        return format!("{name} — {zone_name}");
+    };
+
+    // Computed when drawing rather than when the snapshot arrives, so the duration stays current between updates.
+    let since = UNIX_EPOCH + Duration::from_secs(since.max(0) as u64);
+    // Clamp at zero in case the exporter's clock is ahead of ours.
+    let duration = format_duration(SystemTime::now().duration_since(since).unwrap_or_default());
+    if person.zone_id.is_some() {
+        format!("{name} — at {zone_name} for {duration}")
+    } else {
+        // Not in any zone, e.g. "Away for 3h".
+        format!("{name} — {zone_name} for {duration}")
+    }
+}
+
 fn get_texture_rect(texture: &Texture) -> Rect {
     let sdl2::render::TextureQuery { width, height, .. } = texture.query();
     Rect::new(0, 0, width, height)
@@ -38,15 +112,44 @@ fn scale_inner_to_outer(outer: Rect, inner: Rect) -> Rect {
     result
 }
 
+/// Drawn behind people that the exporter couldn't read from Home Assistant.
+const UNAVAILABLE_COLOUR: Color = Color::RGB(96, 0, 0);
+/// Drawn behind the code for pairing with an exporter.
+const PAIRING_COLOUR: Color = Color::RGB(0, 48, 96);
+
+/// How many (columns, rows) to split the screen into to fit `num_tiles` tiles.
+pub fn grid_layout(num_tiles: usize) -> (u32, u32) {
+    // Make a square grid of tiles. `grid_size` is how many rows/columns we have.
+    let grid_size = ((num_tiles as f32).sqrt().ceil() as u32).max(1);
+
+    let num_columns = grid_size;
+    // Sneaky trick: with e.g. 2 tiles, we don't want 2 rows and columns, it wastes half the screen.
+    // Instead, pick the number of columns according to a square, but only use as many rows as
+    // necessary to fit all the tiles with that many columns.
+    let num_rows = ((num_tiles as f32 / num_columns as f32).ceil() as u32).max(1);
+    (num_columns, num_rows)
+}
+
+/// The (width, height) of each tile when `num_tiles` tiles are drawn on a screen of the given size.
+pub fn tile_size((screen_width, screen_height): (u32, u32), num_tiles: usize) -> (u32, u32) {
+    let (num_columns, num_rows) = grid_layout(num_tiles);
+    (screen_width / num_columns, screen_height / num_rows)
+}
+
 pub struct Tile<'a> {
     person_texture: Option<Texture<'a>>,
     background_texture: Option<Texture<'a>>,
+    /// Used when there's no background texture.
+    background_colour: Color,
+    caption_texture: Texture<'a>,
 }
 impl<'a> Tile<'a> {
     pub fn new<T>(
         texture_creator: &'a TextureCreator<T>,
+        font: &Font,
         person: &clock_pb::Person,
         zone: Option<&clock_pb::Zone>,
+        stale_since: Option<SystemTime>,
     ) -> Result<Self, String> {
         let person_texture = person
             .photo_data
@@ -61,7 +164,7 @@ impl<'a> Tile<'a> {
         if zone_texture.is_none() {
             log::trace!(
                 "Using blank zone texture for {}, no zone photo data provided.",
-                &person.id
+                person.id
             );
         }
 
@@ -75,9 +178,52 @@ impl<'a> Tile<'a> {
             }
         }
 
+        let caption_texture = text_to_texture(
+            texture_creator,
+            font,
+            &make_caption(person, zone, stale_since),
+        )?;
+
         Ok(Tile {
             person_texture,
             background_texture: zone_texture,
+            background_colour: Color::BLACK,
+            caption_texture,
+        })
+    }
+
+    /// A placeholder for a person that the exporter reported an error for.
+    pub fn new_unavailable<T>(
+        texture_creator: &'a TextureCreator<T>,
+        font: &Font,
+        error: &clock_pb::EntityError,
+    ) -> Result<Self, String> {
+        log::trace!(
+            "Marking {} as unavailable: {}",
+            error.entity_id,
+            error.message
+        );
+        let caption = format!("{} — unavailable", error.entity_id);
+        Ok(Tile {
+            person_texture: None,
+            background_texture: None,
+            background_colour: UNAVAILABLE_COLOUR,
+            caption_texture: text_to_texture(texture_creator, font, &caption)?,
+        })
+    }
+
+    /// Shows the code that the exporter's owner needs to approve pairing with.
+    pub fn new_pairing<T>(
+        texture_creator: &'a TextureCreator<T>,
+        font: &Font,
+        code: &str,
+    ) -> Result<Self, String> {
+        let caption = format!("To pair, run: exporter pair {code} <name>");
+        Ok(Tile {
+            person_texture: None,
+            background_texture: None,
+            background_colour: PAIRING_COLOUR,
+            caption_texture: text_to_texture(texture_creator, font, &caption)?,
         })
     }
 
@@ -86,17 +232,25 @@ impl<'a> Tile<'a> {
         canvas: &mut Canvas<T>,
         dest: Rect,
     ) -> Result<(), String> {
-        Self::draw_background(&self.background_texture, canvas, dest)?;
+        Self::draw_background(
+            &self.background_texture,
+            self.background_colour,
+            canvas,
+            dest,
+        )?;
 
         if let Some(person_texture) = &self.person_texture {
             Self::draw_person(person_texture, canvas, dest)?;
         }
 
+        Self::draw_caption(&self.caption_texture, canvas, dest)?;
+
         Ok(())
     }
 
     pub fn draw_background<T: sdl2::render::RenderTarget>(
         background_texture: &Option<Texture>,
+        background_colour: Color,
         canvas: &mut Canvas<T>,
         dest: Rect,
     ) -> Result<(), String> {
@@ -110,7 +264,7 @@ impl<'a> Tile<'a> {
                 canvas.copy(texture, scaled_background_src, dest)?;
             }
             None => {
-                canvas.set_draw_color(sdl2::pixels::Color::BLACK);
+                canvas.set_draw_color(background_colour);
                 canvas.fill_rect(dest)?;
             }
         }
@@ -140,38 +294,110 @@ impl<'a> Tile<'a> {
         canvas.copy(person_texture, None, person_dest)?;
         Ok(person_dest)
     }
+
+    /// Draw the caption centred along the bottom of the destination, over a translucent band so that it's
+    /// readable on top of any background.
+    pub fn draw_caption<T: sdl2::render::RenderTarget>(
+        caption_texture: &Texture,
+        canvas: &mut Canvas<T>,
+        dest: Rect,
+    ) -> Result<(), String> {
+        const MARGIN: u32 = 8;
+        let text_rect = get_texture_rect(caption_texture);
+        // Shrink long captions to fit the tile, rather than cutting them off.
+        let max_width = dest.width().saturating_sub(2 * MARGIN).max(1);
+        let scale = (max_width as f32 / text_rect.width() as f32).min(1.0);
+        let mut text_dest = Rect::new(
+            0,
+            0,
+            ((text_rect.width() as f32 * scale) as u32).max(1),
+            ((text_rect.height() as f32 * scale) as u32).max(1),
+        );
+
+        let band_height = (text_dest.height() + 2 * MARGIN).min(dest.height());
+        let band = Rect::new(
+            dest.x(),
+            dest.bottom() - band_height as i32,
+            dest.width(),
+            band_height,
+        );
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+        canvas.fill_rect(band)?;
+
+        text_dest.center_on(band.center());
+        canvas.copy(caption_texture, None, text_dest)
+    }
+}
+
+/// People (including device trackers standing in for people) the exporter reported errors for. Zone errors
+/// aren't included: they just mean the people in them are drawn without a background.
+fn unavailable_people(snapshot: &Snapshot) -> impl Iterator<Item = &clock_pb::EntityError> {
+    snapshot.errors.iter().filter(|e| {
+        e.entity_id.starts_with("person.") || e.entity_id.starts_with("device_tracker.")
+    })
+}
+
+/// How many tiles `snapshots_to_tiles` will produce, without rendering anything.
+pub fn count_tiles(snapshots: &EndpointSnapshots) -> usize {
+    snapshots
+        .iter()
+        .map(|s| {
+            s.people.len() + unavailable_people(s).count() + usize::from(s.pairing_code.is_some())
+        })
+        .sum()
 }
 
 pub fn snapshot_to_tiles<'a, T>(
     texture_creator: &'a TextureCreator<T>,
+    font: &Font,
     snapshot: &Snapshot,
 ) -> Vec<Tile<'a>> {
     let mut tiles = vec![];
+    if let Some(code) = &snapshot.pairing_code {
+        match Tile::new_pairing(texture_creator, font, code) {
+            Ok(image) => tiles.push(image),
+            Err(e) => log::error!("Failed to render pairing code: {e}"),
+        }
+    }
     let mut sorted_people = snapshot.people.clone();
     sorted_people.sort_by_key(|p| p.id.clone());
 
     for person in sorted_people {
         match Tile::new(
             texture_creator,
+            font,
             &person,
             person
                 .zone_id
                 .as_ref()
                 .and_then(|id| snapshot.zones.get(id)),
+            snapshot.stale_since,
         ) {
             Ok(image) => tiles.push(image),
             Err(e) => log::error!("Failed to render {person:?}: {e}"),
         }
     }
+
+    // Show people that couldn't be read rather than silently dropping them.
+    let mut unavailable_people: Vec<_> = unavailable_people(snapshot).collect();
+    unavailable_people.sort_by_key(|e| &e.entity_id);
+    for error in unavailable_people {
+        match Tile::new_unavailable(texture_creator, font, error) {
+            Ok(image) => tiles.push(image),
+            Err(e) => log::error!("Failed to render {error:?}: {e}"),
+        }
+    }
     tiles
 }
 
 pub fn snapshots_to_tiles<'a, T>(
     texture_creator: &'a TextureCreator<T>,
+    font: &Font,
     snapshots: &EndpointSnapshots,
 ) -> Vec<Tile<'a>> {
     snapshots
         .iter()
-        .flat_map(|s| snapshot_to_tiles(texture_creator, s))
+        .flat_map(|s| snapshot_to_tiles(texture_creator, font, s))
         .collect()
 }
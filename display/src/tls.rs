@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{PrivateKeyDer, ServerName};
+
+use crate::config::Endpoint;
+
+/// Connect to an exporter, over TLS if its URI is `https://`. The TLS settings are per-endpoint, so that
+/// exporters with self-signed certificates or their own CA can be used. `configure` sets any other options
+/// on the connection.
+pub async fn connect(
+    endpoint: &Endpoint,
+    configure: impl FnOnce(tonic::transport::Endpoint) -> tonic::transport::Endpoint,
+) -> Result<tonic::transport::Channel, String> {
+    let uses_tls_options = endpoint.ca_certificates_file.is_some()
+        || endpoint.pinned_certificate_sha256.is_some()
+        || endpoint.tls_domain_name.is_some()
+        || endpoint.client_certificate_file.is_some()
+        || endpoint.client_key_file.is_some();
+    if endpoint.uri.scheme() != Some(&tonic::codegen::http::uri::Scheme::HTTPS) {
+        if uses_tls_options {
+            return Err("TLS options are only used with https:// URIs".to_string());
+        }
+        return configure(tonic::transport::Channel::builder(endpoint.uri.clone()))
+            .connect()
+            .await
+            .map_err(|e| format!("Failed to connect: {e}"));
+    }
+
+    let host = match &endpoint.tls_domain_name {
+        Some(domain_name) => domain_name.as_str(),
+        None => lib::tls::uri_host(&endpoint.uri).ok_or("URI has no host")?,
+    };
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| format!("Invalid TLS domain name {host}: {e}"))?;
+
+    // `lib::tls::connect` does the TLS itself, so tonic has to think it's plaintext.
+    let mut parts = endpoint.uri.clone().into_parts();
+    parts.scheme = Some(tonic::codegen::http::uri::Scheme::HTTP);
+    let uri = tonic::transport::Uri::from_parts(parts).map_err(|e| e.to_string())?;
+
+    let builder = configure(tonic::transport::Channel::builder(uri));
+    lib::tls::connect(builder, client_config(endpoint)?, server_name)
+        .await
+        .map_err(|e| format!("Failed to connect: {e}"))
+}
+
+fn client_config(endpoint: &Endpoint) -> Result<rustls::ClientConfig, String> {
+    let builder = rustls::ClientConfig::builder_with_provider(lib::tls::crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let builder = match (
+        &endpoint.pinned_certificate_sha256,
+        &endpoint.ca_certificates_file,
+    ) {
+        (Some(_), Some(_)) => {
+            return Err(
+                "Set at most one of pinned_certificate_sha256 and ca_certificates_file".to_string(),
+            )
+        }
+        (Some(fingerprint), None) => {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(
+                    lib::tls::PinnedCertificate::from_fingerprint(fingerprint.clone()),
+                ))
+        }
+        (None, Some(path)) => {
+            let pem =
+                std::fs::read_to_string(path).map_err(|e| format!("When opening {path:?}: {e}"))?;
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in lib::tls::parse_certificates(&pem)? {
+                roots
+                    .add(certificate)
+                    .map_err(|e| format!("Invalid CA certificate in {path:?}: {e}"))?;
+            }
+            builder.with_root_certificates(roots)
+        }
+        (None, None) => builder.with_root_certificates(rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        }),
+    };
+    match (&endpoint.client_certificate_file, &endpoint.client_key_file) {
+        (Some(certificate_path), Some(key_path)) => {
+            let pem = std::fs::read_to_string(certificate_path)
+                .map_err(|e| format!("When opening {certificate_path:?}: {e}"))?;
+            let certificates = lib::tls::parse_certificates(&pem)?;
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .map_err(|e| format!("Invalid private key in {key_path:?}: {e}"))?;
+            builder
+                .with_client_auth_cert(certificates, key)
+                .map_err(|e| format!("Invalid client certificate: {e}"))
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err("client_certificate_file and client_key_file must be set together".to_string()),
+    }
+}
@@ -0,0 +1,334 @@
+//! A record of who has been sent the household's locations, so the exporter's owner can check what each display
+//! has seen. One JSON record per line, rotated into numbered files once it grows too large.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use lib::clock_pb::GetPeopleLocationsResponse;
+
+use crate::authentication::{self, ClientIdentity};
+use crate::config::AuditConfig;
+
+/// Within `AUDIT_LOG_DIRECTORY`. Older records are in `audit.jsonl.1`, `audit.jsonl.2`, etc.
+const AUDIT_LOG_FILE_NAME: &str = "audit.jsonl";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid audit record: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestKind {
+    Get,
+    /// Recorded for each update sent to a watching display.
+    Watch,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Record {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub request: RequestKind,
+    /// The address the request came from.
+    pub peer: Option<String>,
+    pub display: Option<String>,
+    /// The display's client certificate, if it presented one.
+    pub client_certificate: Option<String>,
+    pub privacy_enabled: bool,
+    pub people: Vec<DisclosedPerson>,
+    pub zones: Vec<String>,
+    /// The display already had these locations, so they weren't sent again.
+    #[serde(default)]
+    pub unchanged: bool,
+    /// Nothing was sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DisclosedPerson {
+    pub id: String,
+    /// Whether the person's location was hidden, by the privacy switch or because the display can't see
+    /// their zone.
+    pub location_hidden: bool,
+    pub zone_id: Option<String>,
+}
+/// What was decided while building a response, since it can't always be told from the response itself.
+#[derive(Debug, Default)]
+pub struct Disclosure {
+    pub privacy_enabled: bool,
+    /// People whose locations were hidden, by the privacy switch or because the display can't see their zone.
+    pub hidden_locations: BTreeSet<String>,
+}
+
+impl Record {
+    /// Who made the request. The rest is filled in by `disclosed` or `failed`.
+    pub fn new<T>(request: &tonic::Request<T>, kind: RequestKind) -> Self {
+        Record {
+            time: chrono::Utc::now(),
+            request: kind,
+            peer: request.remote_addr().map(|a| a.to_string()),
+            display: authentication::authenticated_display(request)
+                .ok()
+                .map(|d| d.name.clone()),
+            client_certificate: request
+                .extensions()
+                .get::<ClientIdentity>()
+                .map(|i| i.to_string()),
+            privacy_enabled: false,
+            people: vec![],
+            zones: vec![],
+            unchanged: false,
+            error: None,
+        }
+    }
+
+    pub fn disclosed(
+        &self,
+        response: &GetPeopleLocationsResponse,
+        disclosure: &Disclosure,
+        unchanged: bool,
+    ) -> Self {
+        Record {
+            time: chrono::Utc::now(),
+            privacy_enabled: disclosure.privacy_enabled,
+            people: response
+                .people
+                .iter()
+                .map(|p| DisclosedPerson {
+                    id: p.id.clone(),
+                    location_hidden: disclosure.hidden_locations.contains(&p.id),
+                    zone_id: p.zone_id.clone(),
+                })
+                .collect(),
+            zones: response.zones.iter().map(|z| z.id.clone()).collect(),
+            unchanged,
+            ..self.clone()
+        }
+    }
+
+    pub fn failed(&self, status: &tonic::Status) -> Self {
+        Record {
+            time: chrono::Utc::now(),
+            error: Some(status.message().to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Records are written by a background thread, so requests don't wait for the disk (or for the log to be rotated).
+/// Clones share the same thread.
+#[derive(Clone)]
+pub struct AuditLog {
+    records: mpsc::Sender<Record>,
+}
+struct AuditFile {
+    config: AuditConfig,
+    file: std::fs::File,
+    size: u64,
+}
+
+/// The current file is 0, older files have higher numbers.
+fn path(config: &AuditConfig, number: u32) -> PathBuf {
+    match number {
+        0 => config.directory.join(AUDIT_LOG_FILE_NAME),
+        n => config.directory.join(format!("{AUDIT_LOG_FILE_NAME}.{n}")),
+    }
+}
+
+fn open(config: &AuditConfig) -> Result<AuditFile, Error> {
+    std::fs::create_dir_all(&config.directory)?;
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path(config, 0))?;
+    Ok(AuditFile {
+        config: config.clone(),
+        size: file.metadata()?.len(),
+        file,
+    })
+}
+
+fn ignore_not_found(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl AuditFile {
+    /// Failures are logged rather than failing the request, so a full disk doesn't stop displays working.
+    fn write(&mut self, record: &Record) {
+        let result = serde_json::to_vec(record)
+            .map_err(Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.append(&line)
+            });
+        if let Err(e) = result {
+            log::error!("Unable to write audit record {record:?}: {e}");
+        }
+    }
+
+    fn append(&mut self, line: &[u8]) -> Result<(), Error> {
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        let config = &self.config;
+        ignore_not_found(std::fs::remove_file(path(config, config.max_files)))?;
+        for number in (0..config.max_files).rev() {
+            ignore_not_found(std::fs::rename(
+                path(config, number),
+                path(config, number + 1),
+            ))?;
+        }
+        *self = open(&self.config)?;
+        Ok(())
+    }
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> Result<Self, Error> {
+        let mut file = open(config)?;
+        let (records, received) = mpsc::channel::<Record>();
+        // Stops once every clone of the log has been dropped.
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for record in received {
+                    file.write(&record);
+                }
+            })?;
+        Ok(AuditLog { records })
+    }
+
+    pub fn record(&self, record: Record) {
+        if let Err(mpsc::SendError(record)) = self.records.send(record) {
+            log::error!("Unable to write audit record {record:?}: the audit log has stopped");
+        }
+    }
+}
+
+/// Records from the rotated files and the current one, oldest first.
+fn read_records(config: &AuditConfig) -> Result<Vec<Record>, Error> {
+    let mut records = vec![];
+    for number in (0..=config.max_files).rev() {
+        let file = match std::fs::File::open(path(config, number)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                // E.g. a line cut short by a crash.
+                Err(e) => log::warn!("Skipping unreadable audit record: {e}"),
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[derive(Default)]
+struct DisplaySummary {
+    requests: usize,
+    failed: usize,
+    last: Option<chrono::DateTime<chrono::Utc>>,
+    peers: BTreeSet<String>,
+    client_certificates: BTreeSet<String>,
+    /// How many times each person's location was shown and hidden.
+    people: BTreeMap<String, (usize, usize)>,
+    zones: BTreeSet<String>,
+}
+
+/// A human-readable summary of what each display has been sent, optionally only since a given time.
+pub fn summarise(
+    config: &AuditConfig,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<String, Error> {
+    let records: Vec<Record> = read_records(config)?
+        .into_iter()
+        .filter(|r| since.is_none_or(|since| r.time >= since))
+        .collect();
+    let (Some(first), Some(last)) = (records.first(), records.last()) else {
+        return Ok("No audit records.".to_string());
+    };
+    let mut summary = format!(
+        "{} requests from {} to {}\n",
+        records.len(),
+        first.time,
+        last.time
+    );
+
+    let mut displays: BTreeMap<String, DisplaySummary> = BTreeMap::new();
+    for record in &records {
+        let name = record
+            .display
+            .as_deref()
+            .unwrap_or("an unauthenticated display");
+        let display = displays.entry(name.to_string()).or_default();
+        display.requests += 1;
+        display.last = Some(record.time);
+        // Without the port, which changes with every connection.
+        display.peers.extend(record.peer.as_ref().map(|peer| {
+            peer.parse::<std::net::SocketAddr>()
+                .map_or(peer.clone(), |address| address.ip().to_string())
+        }));
+        display
+            .client_certificates
+            .extend(record.client_certificate.clone());
+        if record.error.is_some() {
+            display.failed += 1;
+            continue;
+        }
+        for person in &record.people {
+            let (shown, hidden) = display.people.entry(person.id.clone()).or_default();
+            if person.location_hidden {
+                *hidden += 1;
+            } else {
+                *shown += 1;
+            }
+        }
+        display.zones.extend(record.zones.iter().cloned());
+    }
+
+    for (name, display) in displays {
+        summary += &format!(
+            "\n{name}: {} requests ({} failed), last at {}\n",
+            display.requests,
+            display.failed,
+            display.last.map(|t| t.to_string()).unwrap_or_default(),
+        );
+        let join = |set: &BTreeSet<String>| set.iter().cloned().collect::<Vec<_>>().join(", ");
+        summary += &format!("  From: {}\n", join(&display.peers));
+        if !display.client_certificates.is_empty() {
+            summary += &format!(
+                "  Client certificates: {}\n",
+                join(&display.client_certificates)
+            );
+        }
+        for (person, (shown, hidden)) in &display.people {
+            summary += &format!("  {person}: location sent {shown} times, hidden {hidden} times\n");
+        }
+        if !display.zones.is_empty() {
+            summary += &format!("  Zones: {}\n", join(&display.zones));
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,23 @@
+use lib::clock_pb::auth_service_server::AuthService;
+use lib::clock_pb::{GetChallengeRequest, GetChallengeResponse};
+use lib::password::Challenges;
+
+/// Hands out the challenges that displays authenticate `ClockService` requests with.
+pub struct AuthServer {
+    challenges: Challenges,
+}
+impl AuthServer {
+    pub fn new(challenges: Challenges) -> Self {
+        AuthServer { challenges }
+    }
+}
+
+#[tonic::async_trait]
+impl AuthService for AuthServer {
+    async fn get_challenge(
+        &self,
+        _: tonic::Request<GetChallengeRequest>,
+    ) -> tonic::Result<tonic::Response<GetChallengeResponse>> {
+        Ok(tonic::Response::new(self.challenges.issue()?))
+    }
+}
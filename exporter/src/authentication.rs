@@ -0,0 +1,216 @@
+use std::sync::{Arc, RwLock};
+
+use lib::password::{Challenges, CheckPassword, LockoutPolicy, StoredPassword};
+
+use crate::config::DisplayConfig;
+use crate::tokens::Tokens;
+
+/// Who a request came from, if the display presented a client certificate. Added to the request's
+/// extensions by `Authenticate`.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    /// E.g. `CN=Kitchen display`.
+    pub subject: String,
+    /// As produced by `lib::tls::fingerprint`.
+    pub fingerprint: String,
+}
+impl std::fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (certificate {})", self.subject, self.fingerprint)
+    }
+}
+
+/// The client certificate has already been verified during the TLS handshake, so can be trusted.
+fn client_identity(request: &tonic::Request<()>) -> Option<ClientIdentity> {
+    let certificates = request.peer_certs()?;
+    let certificate = certificates.first()?;
+    let subject = match x509_parser::parse_x509_certificate(certificate) {
+        Ok((_, parsed)) => parsed.subject().to_string(),
+        Err(e) => {
+            log::warn!("Unable to parse client certificate: {e}");
+            "an unparseable certificate".to_string()
+        }
+    };
+    Some(ClientIdentity {
+        subject,
+        fingerprint: lib::tls::fingerprint(certificate),
+    })
+}
+
+/// Describe who a request came from, for logging.
+pub fn describe_client<T>(request: &tonic::Request<T>) -> String {
+    let name = match request.extensions().get::<Arc<DisplayConfig>>() {
+        Some(display) => format!("display '{}'", display.name),
+        None => "an unauthenticated display".to_string(),
+    };
+    match request.extensions().get::<ClientIdentity>() {
+        Some(identity) => format!("{name}, {identity}"),
+        None => name,
+    }
+}
+
+/// The display that `Authenticate` matched the request to.
+pub fn authenticated_display<T>(request: &tonic::Request<T>) -> tonic::Result<Arc<DisplayConfig>> {
+    request
+        .extensions()
+        .get::<Arc<DisplayConfig>>()
+        .cloned()
+        .ok_or(tonic::Status::unauthenticated(
+            "Request wasn't authenticated.",
+        ))
+}
+
+/// Works out which of the configured displays a request to `ClockService` is from, based on its password
+/// and/or client certificate. The display is added to the request's extensions, to decide what it can see, along
+/// with the `TokenClaims` if it used a token.
+/// Clones share the same displays, so that displays added by pairing can connect straight away.
+#[derive(Clone)]
+pub struct Authenticate {
+    known: Arc<RwLock<Arc<KnownDisplays>>>,
+    tokens: Option<Tokens>,
+}
+struct KnownDisplays {
+    displays: Vec<Arc<DisplayConfig>>,
+    /// The passwords of `displays` that have one, in the order given to `check_password`.
+    passwords: Vec<StoredPassword>,
+    check_password: CheckPassword,
+}
+impl KnownDisplays {
+    fn new(displays: Vec<Arc<DisplayConfig>>, check_password: &CheckPassword) -> Self {
+        let passwords: Vec<StoredPassword> = displays
+            .iter()
+            .filter_map(|d| d.stored_password())
+            .collect();
+        KnownDisplays {
+            check_password: check_password.with_passwords(passwords.clone()),
+            displays,
+            passwords,
+        }
+    }
+}
+impl Authenticate {
+    pub fn new(
+        displays: &[DisplayConfig],
+        challenges: Challenges,
+        allow_plaintext: bool,
+        lockout_policy: LockoutPolicy,
+        tokens: Option<Tokens>,
+    ) -> Self {
+        let check_password =
+            CheckPassword::new(vec![], challenges, allow_plaintext, lockout_policy);
+        let displays = displays.iter().cloned().map(Arc::new).collect();
+        Authenticate {
+            known: Arc::new(RwLock::new(Arc::new(KnownDisplays::new(
+                displays,
+                &check_password,
+            )))),
+            tokens,
+        }
+    }
+
+    pub fn tokens(&self) -> Option<&Tokens> {
+        self.tokens.as_ref()
+    }
+
+    /// Start accepting another display, e.g. once it's been paired.
+    pub fn add_display(&self, display: DisplayConfig) {
+        let mut known = self.known.write().unwrap();
+        let mut displays = known.displays.clone();
+        displays.push(Arc::new(display));
+        *known = Arc::new(KnownDisplays::new(displays, &known.check_password));
+    }
+}
+
+/// Whether the credentials presented with a request are enough for `display`.
+fn accepts(
+    display: &DisplayConfig,
+    password: Option<&StoredPassword>,
+    identity: Option<&ClientIdentity>,
+) -> bool {
+    let expected = display.stored_password();
+    if expected.is_some() && expected.as_ref() != password {
+        return false;
+    }
+    if display.uses_client_certificate() {
+        let Some(identity) = identity else {
+            return false;
+        };
+        if display
+            .client_certificate_sha256
+            .as_ref()
+            .is_some_and(|f| *f != identity.fingerprint)
+            || display
+                .client_certificate_subject
+                .as_ref()
+                .is_some_and(|s| *s != identity.subject)
+        {
+            return false;
+        }
+    }
+    true
+}
+
+impl tonic::service::Interceptor for Authenticate {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        let known = self.known.read().unwrap().clone();
+        let identity = client_identity(&request);
+        known.check_password.check_allowed(&request)?;
+        // For failures that `CheckPassword` hasn't already recorded.
+        let failed = |status: tonic::Status| {
+            known.check_password.record_failure(&request, &status);
+            status
+        };
+
+        if let Some(claims) = lib::password::request_token(&request) {
+            let tokens = self.tokens.as_ref().ok_or_else(|| {
+                failed(tonic::Status::unauthenticated(
+                    "This exporter doesn't accept tokens.",
+                ))
+            })?;
+            let (claims, display, secret) = tokens.check(claims).map_err(failed)?;
+            known
+                .check_password
+                .with_passwords(vec![StoredPassword::Plaintext(secret)])
+                .check(&request)?
+                .ok_or_else(|| {
+                    failed(tonic::Status::unauthenticated(
+                        "No MAC provided with the token.",
+                    ))
+                })?;
+            request.extensions_mut().insert(Arc::new(display));
+            request.extensions_mut().insert(claims);
+            if let Some(identity) = identity {
+                request.extensions_mut().insert(identity);
+            }
+            return Ok(request);
+        }
+
+        let password = known
+            .check_password
+            .check(&request)?
+            .map(|index| &known.passwords[index]);
+        if password.is_none() && identity.is_none() {
+            return Err(failed(tonic::Status::unauthenticated(
+                "No password or client certificate provided.",
+            )));
+        }
+
+        let display = known
+            .displays
+            .iter()
+            .find(|d| accepts(d, password, identity.as_ref()))
+            .ok_or_else(|| {
+                failed(tonic::Status::unauthenticated(
+                    "Credentials don't match any display.",
+                ))
+            })?;
+        request.extensions_mut().insert(display.clone());
+        if let Some(identity) = identity {
+            request.extensions_mut().insert(identity);
+        }
+        Ok(request)
+    }
+}
@@ -1,14 +1,31 @@
-use crate::config;
-use crate::homeassistant;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::audit::{self, AuditLog};
+use crate::authentication::{self, Authenticate};
+use crate::compression;
+use crate::config::{self, DisplayConfig};
+use crate::homeassistant::{self, EntityId};
+use crate::homeassistant_live::LiveSnapshot;
+use crate::homeassistant_retry::{ReadSnapshot, SnapshotReader};
 use crate::photo_manager;
+use crate::photo_resizer::PhotoResizer;
+use crate::photo_store::PhotoStore;
+use crate::tokens::{TokenClaims, Tokens};
 
 use lib::clock_pb;
 use lib::clock_pb::clock_service_server::{ClockService, ClockServiceServer};
-use lib::clock_pb::{GetPeopleLocationsRequest, GetPeopleLocationsResponse};
-use lib::password::CheckPassword;
+use lib::clock_pb::{
+    Feature, GetExporterInfoRequest, GetExporterInfoResponse, GetPeopleLocationsRequest,
+    GetPeopleLocationsResponse, GetPhotoRequest, GetPhotoResponse,
+};
 
 fn get_entity_photo(
-    entity_id: &impl homeassistant::EntityId,
+    entity_id: &impl std::fmt::Display,
     photo_manager: &photo_manager::PhotoManager,
 ) -> Option<Vec<u8>> {
     match photo_manager.get_photo(entity_id) {
@@ -20,123 +37,437 @@ fn get_entity_photo(
     }
 }
 
+/// Errors are only reported for people and zones the display can see, since they name the entity.
+fn can_see_entity(display: &DisplayConfig, entity_id: &str) -> bool {
+    if let Ok(id) = homeassistant::TrackedEntityId::new(entity_id) {
+        display.can_see_person(&id)
+    } else if let Ok(id) = homeassistant::ZoneId::new(entity_id) {
+        display.can_see_zone(&id)
+    } else {
+        true
+    }
+}
+
+// Cloned into the background task serving each `WatchPeopleLocations` stream.
+#[derive(Clone)]
 pub struct ClockServer {
-    homeassistant_connection_config: config::HomeAssistantConfig,
-    person_ids: Vec<homeassistant::PersonId>,
+    homeassistant: homeassistant::SharedClient,
+    snapshots: SnapshotReader,
+    person_ids: Vec<homeassistant::TrackedEntityId>,
     privacy_switch_entity_id: Option<homeassistant::InputBooleanId>,
     photo_manager: photo_manager::PhotoManager,
+    fetched_photos: photo_manager::FetchedPhotos,
+    photo_store: PhotoStore,
+    photo_resizer: PhotoResizer,
+    watch_poll_interval: Duration,
+    household_name: Option<String>,
+    compression: Vec<tonic::codec::CompressionEncoding>,
+    audit: Option<AuditLog>,
+    /// For rechecking tokens while watching.
+    tokens: Option<Tokens>,
+    /// Kept up to date by HA. While it's not connected, each request reads from HA instead.
+    live: Option<LiveSnapshot>,
 }
 impl ClockServer {
     pub fn make_server(
-        password: secstr::SecStr,
-        homeassistant_connection_config: config::HomeAssistantConfig,
-        person_ids: Vec<homeassistant::PersonId>,
-        privacy_switch_entity_id: &Option<homeassistant::InputBooleanId>,
-        photo_manager: photo_manager::PhotoManager,
+        config: &config::Config,
+        authenticate: Authenticate,
+        audit: Option<AuditLog>,
+        homeassistant: homeassistant::SharedClient,
+        live: Option<LiveSnapshot>,
     ) -> tonic::service::interceptor::InterceptedService<
         ClockServiceServer<ClockServer>,
-        CheckPassword,
+        Authenticate,
     > {
         let server = ClockServer {
-            homeassistant_connection_config,
-            person_ids,
-            privacy_switch_entity_id: privacy_switch_entity_id.clone(),
-            photo_manager,
+            snapshots: SnapshotReader::new(homeassistant.clone(), &config.homeassistant),
+            homeassistant,
+            person_ids: config.person_entity_ids.clone(),
+            privacy_switch_entity_id: config.privacy_switch_entity_id.clone(),
+            photo_manager: photo_manager::PhotoManager::new(config.photo_directory.clone()),
+            fetched_photos: photo_manager::FetchedPhotos::default(),
+            photo_store: PhotoStore::default(),
+            photo_resizer: PhotoResizer::default(),
+            watch_poll_interval: config.watch_poll_interval,
+            household_name: config.household_name.clone(),
+            compression: config.compression.clone(),
+            audit,
+            tokens: authenticate.tokens().cloned(),
+            live,
+        };
+        // Only used if the display says it accepts the encoding, so older displays still get uncompressed
+        // responses.
+        let mut service = ClockServiceServer::new(server);
+        for encoding in &config.compression {
+            service = service
+                .accept_compressed(*encoding)
+                .send_compressed(*encoding);
+        }
+        tonic::service::interceptor::InterceptedService::new(service, authenticate)
+    }
+
+    /// Produce the `photo_data` and `photo_digest` fields for a proto, resized and sent however the display asked.
+    async fn photo_fields(
+        &self,
+        request: &GetPeopleLocationsRequest,
+        photo_data: Option<Vec<u8>>,
+    ) -> (Option<Vec<u8>>, Option<String>) {
+        let photo_data = match (photo_data, request.tile_size.or(request.screen_size)) {
+            (Some(data), Some(size)) => Some(self.photo_resizer.resize(data, size).await),
+            (data, _) => data,
         };
-        ClockServiceServer::with_interceptor(server, CheckPassword::new(password))
+        match photo_data {
+            Some(data) if request.photo_digests_only => (None, Some(self.photo_store.insert(data))),
+            data => (data, None),
+        }
+    }
+
+    fn record(&self, record: audit::Record) {
+        if let Some(audit) = &self.audit {
+            audit.record(record);
+        }
     }
 
+    /// Only includes what `display` is allowed to see, also returning what was hidden. Photos are only fetched from HA if they haven't been
+    /// already, and not at all if `stale_age` is set because HA couldn't be read.
     async fn snapshot_to_response(
         &self,
+        display: &DisplayConfig,
+        request: &GetPeopleLocationsRequest,
         client: &homeassistant::Client,
-        snapshot: homeassistant::Snapshot,
-    ) -> GetPeopleLocationsResponse {
+        snapshot: &homeassistant::Snapshot,
+        stale_age: Option<Duration>,
+    ) -> (GetPeopleLocationsResponse, audit::Disclosure) {
+        let privacy_enabled = snapshot.privacy_enabled;
+        let mut disclosure = audit::Disclosure {
+            privacy_enabled,
+            ..Default::default()
+        };
+
         let mut people = vec![];
-        for person in snapshot.people {
+        // Zones are only sent if someone the display can see is in them, so they don't give away anyone else.
+        let mut occupied_zone_ids = std::collections::BTreeSet::new();
+        for person in &snapshot.people {
+            if !display.can_see_person(&person.id) {
+                continue;
+            }
             let photo_data: Option<Vec<u8>>;
             if let Some(pd) = get_entity_photo(&person.id, &self.photo_manager) {
                 photo_data = Some(pd);
+            } else if let Some(pd) = self.fetched_photos.get(person) {
+                photo_data = Some(pd.to_vec());
+            } else if stale_age.is_some() {
+                photo_data = None;
             } else {
-                log::info!(
-                    "No photo file for '{}', trying to fetch from HA",
-                    &person.id
-                );
-                match client.get_photo(&person).await {
-                    Ok(pd) => photo_data = pd,
+                log::info!("No photo file for '{}', trying to fetch from HA", person.id);
+                match client.get_photo(person).await {
+                    Ok(pd) => {
+                        if let Some(pd) = &pd {
+                            self.fetched_photos.insert(person, pd.clone());
+                        }
+                        photo_data = pd;
+                    }
                     Err(e) => {
-                        log::error!("Failed to get photo for {}: {}", &person.id, e);
+                        log::error!("Failed to get photo for {}: {}", person.id, e);
                         photo_data = None;
                     }
                 }
             }
 
+            let (photo_data, photo_digest) = self.photo_fields(request, photo_data).await;
+            // People in zones the display can't see are shown the same as with privacy enabled.
+            let hide_location = privacy_enabled
+                || person
+                    .zone_id
+                    .as_ref()
+                    .is_some_and(|id| !display.can_see_zone(id));
+            if hide_location {
+                disclosure.hidden_locations.insert(person.id.to_string());
+            } else {
+                occupied_zone_ids.extend(person.zone_id.as_ref());
+            }
             people.push(clock_pb::Person {
                 photo_data,
+                photo_digest,
+                name: person.get_friendly_name(),
+                // The state names the zone, so has to be hidden along with the zones themselves.
+                zone_name: (!hide_location).then(|| person.get_zone_display_name()),
+                in_zone_since_unix_seconds: person
+                    .last_changed
+                    .filter(|_| !hide_location)
+                    .map(|t| t.timestamp()),
+                // Changes whenever the person moves, so would show when they did.
+                last_updated_unix_seconds: person
+                    .last_updated
+                    .filter(|_| !hide_location)
+                    .map(|t| t.timestamp()),
                 id: person.id.to_string(),
-                zone_id: person.zone_id.map(|id| id.to_string()),
+                zone_id: person
+                    .zone_id
+                    .as_ref()
+                    .filter(|_| !hide_location)
+                    .map(|id| id.to_string()),
+            })
+        }
+
+        let mut zones = vec![];
+        for zone_id in occupied_zone_ids {
+            let Some(zone) = snapshot.zones.get(zone_id) else {
+                continue;
+            };
+            let (photo_data, photo_digest) = self
+                .photo_fields(request, get_entity_photo(zone_id, &self.photo_manager))
+                .await;
+            zones.push(clock_pb::Zone {
+                photo_data,
+                photo_digest,
+                name: zone.get_friendly_name(),
+                id: zone_id.to_string(),
             })
         }
 
-        let privacy_enabled: bool;
-        if let Some(id) = &self.privacy_switch_entity_id {
-            match client.get_entity::<homeassistant::InputBoolean>(id).await {
-                Ok(privacy_input_boolean) => privacy_enabled = privacy_input_boolean.into(),
-                Err(e) => {
-                    log::warn!(
-                        "Unable to fetch {} from HA, assuming privacy is enabled: {e}",
-                        &id
-                    );
-                    privacy_enabled = true;
+        let errors = snapshot
+            .errors
+            .iter()
+            .filter(|e| can_see_entity(display, &e.entity_id))
+            .map(|e| clock_pb::EntityError {
+                entity_id: e.entity_id.clone(),
+                message: e.error.summary().to_string(),
+            })
+            .collect();
+
+        let mut response = GetPeopleLocationsResponse {
+            people,
+            zones,
+            errors,
+            // Only whether it's stale is part of the version, so watchers are told when HA stops or starts
+            // responding, but not sent the same snapshot again each time it gets older.
+            stale_age_seconds: stale_age.map(|_| 0),
+            ..Default::default()
+        };
+        // Any change to the contents is a new version. This relies on the snapshot being in a consistent order.
+        response.version = Some(lib::photo::digest(&response.encode_to_vec()));
+        response.stale_age_seconds =
+            stale_age.map(|age| age.as_secs().try_into().unwrap_or(u32::MAX));
+        (response, disclosure)
+    }
+
+    /// Also returns what was hidden from the display, for the audit log.
+    async fn get_response(
+        &self,
+        display: &DisplayConfig,
+        request: &GetPeopleLocationsRequest,
+    ) -> tonic::Result<(GetPeopleLocationsResponse, audit::Disclosure)> {
+        let person_ids: Vec<_> = self
+            .person_ids
+            .iter()
+            .filter(|id| display.can_see_person(id))
+            .cloned()
+            .collect();
+        // Still needed for photos, even if the snapshot comes from memory.
+        let client = self.homeassistant.get();
+        let snapshot = match self.live.as_ref().and_then(|l| l.snapshot(&person_ids)) {
+            Some(snapshot) => ReadSnapshot {
+                snapshot: self.snapshots.remember(&person_ids, snapshot),
+                stale_age: None,
+            },
+            None => self
+                .snapshots
+                .read(&person_ids, self.privacy_switch_entity_id.as_ref())
+                .await
+                .map_err(|e| {
+                    log::warn!("Unable to get a snapshot: {e}");
+                    tonic::Status::unavailable(e.summary())
+                })?,
+        };
+        log::trace!("Got snapshot: {:?}", snapshot.snapshot);
+
+        let (response, disclosure) = self
+            .snapshot_to_response(
+                display,
+                request,
+                &client,
+                &snapshot.snapshot,
+                snapshot.stale_age,
+            )
+            .await;
+        log::trace!("Responding with: {response:?}");
+        Ok((response, disclosure))
+    }
+
+    /// Poll HA until the display hangs up, sending a response whenever its version differs from the last one
+    /// sent (or the one the display already has). Also checks straight away whenever HA reports a change. If the
+    /// display used a token, the stream ends once it expires or is revoked.
+    async fn watch(
+        self,
+        display: Arc<DisplayConfig>,
+        token: Option<TokenClaims>,
+        record: audit::Record,
+        request: GetPeopleLocationsRequest,
+        encoding: Option<tonic::codec::CompressionEncoding>,
+        tx: mpsc::Sender<tonic::Result<GetPeopleLocationsResponse>>,
+    ) {
+        let mut last_version = request.last_version.clone();
+        let mut live = self.live.clone();
+        let token_expires = token
+            .as_ref()
+            .and_then(|t| t.expires_unix_seconds)
+            .map(|e| UNIX_EPOCH + Duration::from_secs(e.max(0) as u64));
+        let mut revocations = self
+            .tokens
+            .as_ref()
+            .filter(|_| token.is_some())
+            .map(Tokens::subscribe_to_revocations);
+        loop {
+            if let (Some(tokens), Some(token)) = (&self.tokens, &token) {
+                if let Err(status) = tokens.check_still_valid(token) {
+                    log::info!("Ending watch: {}", status.message());
+                    self.record(record.failed(&status));
+                    let _ = tx.send(Err(status)).await;
+                    break;
                 }
             }
-        } else {
-            privacy_enabled = false
-        }
 
-        let mut zones = vec![];
-        if !privacy_enabled {
-            for zone_id in snapshot.zones.keys() {
-                zones.push(clock_pb::Zone {
-                    photo_data: get_entity_photo(zone_id, &self.photo_manager),
-                    id: zone_id.to_string(),
-                })
+            match self.get_response(&display, &request).await {
+                Ok((response, disclosure)) => {
+                    if response.version != last_version {
+                        log::info!("Sending updated locations to watcher");
+                        self.record(record.disclosed(&response, &disclosure, false));
+                        compression::log_response_size("Watch update", &response, encoding);
+                        last_version = response.version.clone();
+                        if tx.send(Ok(response)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                // Keep the stream open: HA being briefly unavailable shouldn't force the display to reconnect.
+                Err(status) => log::warn!("Skipping watch update: {status}"),
             }
-        }
 
-        GetPeopleLocationsResponse { people, zones }
+            tokio::select! {
+                _ = tokio::time::sleep(self.watch_poll_interval) => {},
+                _ = async {
+                    match &mut live {
+                        Some(live) => live.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {},
+                _ = async {
+                    match &mut revocations {
+                        Some(revocations) => {
+                            if revocations.changed().await.is_err() {
+                                std::future::pending::<()>().await
+                            }
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {},
+                _ = async {
+                    match token_expires {
+                        Some(expires) => {
+                            let remaining = expires.duration_since(SystemTime::now()).unwrap_or_default();
+                            tokio::time::sleep(remaining).await
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {},
+                _ = tx.closed() => break,
+            }
+        }
+        log::info!("Watcher disconnected");
     }
 }
 
 #[tonic::async_trait]
 impl ClockService for ClockServer {
+    async fn get_exporter_info(
+        &self,
+        _: tonic::Request<GetExporterInfoRequest>,
+    ) -> tonic::Result<tonic::Response<GetExporterInfoResponse>> {
+        Ok(tonic::Response::new(GetExporterInfoResponse {
+            exporter_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_revision: clock_pb::PROTOCOL_REVISION,
+            features: vec![Feature::Streaming.into(), Feature::PhotoDigests.into()],
+            household_name: self.household_name.clone(),
+        }))
+    }
+
     async fn get_people_locations(
         &self,
-        _: tonic::Request<GetPeopleLocationsRequest>,
+        request: tonic::Request<GetPeopleLocationsRequest>,
     ) -> tonic::Result<tonic::Response<GetPeopleLocationsResponse>> {
-        log::info!("Got request");
-        let maybe_client = homeassistant::Client::new(
-            &self.homeassistant_connection_config.access_token,
-            &self.homeassistant_connection_config.endpoint,
+        log::info!(
+            "Got request from {}",
+            authentication::describe_client(&request)
         );
-        match maybe_client {
-            Ok(client) => match homeassistant::get_snapshot(&client, &self.person_ids).await {
-                Ok(snapshot) => {
-                    log::trace!("Got snapshot: {snapshot:?}");
-
-                    let response = self.snapshot_to_response(&client, snapshot).await;
-                    log::trace!("Responding with: {response:?}");
-                    Ok(tonic::Response::new(response))
-                }
-                Err(e) => {
-                    log::error!("Failed to get snapshot from HA: {e}");
-                    return Err(tonic::Status::unavailable(e.to_string()));
-                }
-            },
-            Err(e) => {
-                let status = tonic::Status::internal(e.to_string());
-                log::error!("Responding with: {e}");
+        let display = authentication::authenticated_display(&request)?;
+        let encoding = compression::negotiated_encoding(request.metadata(), &self.compression);
+        let record = audit::Record::new(&request, audit::RequestKind::Get);
+        let request = request.into_inner();
+        let (response, disclosure) = match self.get_response(&display, &request).await {
+            Ok(response) => response,
+            Err(status) => {
+                self.record(record.failed(&status));
                 return Err(status);
             }
+        };
+        let unchanged = request.last_version.is_some() && request.last_version == response.version;
+        self.record(record.disclosed(&response, &disclosure, unchanged));
+        if unchanged {
+            log::info!("Nothing has changed since the display's last request");
+            return Ok(tonic::Response::new(GetPeopleLocationsResponse {
+                version: response.version,
+                unchanged: true,
+                ..Default::default()
+            }));
+        }
+        compression::log_response_size("Locations response", &response, encoding);
+        Ok(tonic::Response::new(response))
+    }
+
+    type WatchPeopleLocationsStream = ReceiverStream<tonic::Result<GetPeopleLocationsResponse>>;
+
+    async fn watch_people_locations(
+        &self,
+        request: tonic::Request<GetPeopleLocationsRequest>,
+    ) -> tonic::Result<tonic::Response<Self::WatchPeopleLocationsStream>> {
+        log::info!(
+            "Got watch request from {}",
+            authentication::describe_client(&request)
+        );
+        let encoding = compression::negotiated_encoding(request.metadata(), &self.compression);
+        let display = authentication::authenticated_display(&request)?;
+        let token = request.extensions().get::<TokenClaims>().cloned();
+        let record = audit::Record::new(&request, audit::RequestKind::Watch);
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(self.clone().watch(
+            display,
+            token,
+            record,
+            request.into_inner(),
+            encoding,
+            tx,
+        ));
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_photo(
+        &self,
+        request: tonic::Request<GetPhotoRequest>,
+    ) -> tonic::Result<tonic::Response<GetPhotoResponse>> {
+        let encoding = compression::negotiated_encoding(request.metadata(), &self.compression);
+        let digest = &request.get_ref().digest;
+        match self.photo_store.get(digest) {
+            Some(photo_data) => {
+                let response = GetPhotoResponse {
+                    photo_data: photo_data.to_vec(),
+                };
+                compression::log_response_size(&format!("Photo {digest}"), &response, encoding);
+                Ok(tonic::Response::new(response))
+            }
+            None => Err(tonic::Status::not_found(format!(
+                "No photo with digest {digest}"
+            ))),
         }
     }
 }
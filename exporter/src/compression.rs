@@ -0,0 +1,59 @@
+use std::io::Write;
+
+use tonic::codec::CompressionEncoding;
+
+/// The encoding tonic will compress a response with: the first of the display's accepted encodings that
+/// we've enabled.
+pub fn negotiated_encoding(
+    metadata: &tonic::metadata::MetadataMap,
+    enabled: &[CompressionEncoding],
+) -> Option<CompressionEncoding> {
+    let accepted = metadata.get("grpc-accept-encoding")?.to_str().ok()?;
+    accepted.split(',').map(str::trim).find_map(|name| {
+        enabled
+            .iter()
+            .find(|encoding| encoding.to_string() == name)
+            .copied()
+    })
+}
+
+fn compressed_size(data: &[u8], encoding: CompressionEncoding) -> std::io::Result<usize> {
+    // Same settings as tonic uses.
+    match encoding {
+        CompressionEncoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::new(6));
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?.len())
+        }
+        CompressionEncoding::Zstd => {
+            Ok(zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)?.len())
+        }
+        _ => Ok(data.len()),
+    }
+}
+
+/// Log how big a response is, and with debug logging, how big it is after compression. tonic doesn't expose
+/// the compressed size, so finding it out means compressing the response a second time.
+pub fn log_response_size(
+    description: &str,
+    response: &impl prost::Message,
+    encoding: Option<CompressionEncoding>,
+) {
+    let size = response.encoded_len();
+    let Some(encoding) = encoding else {
+        log::info!("{description} is {size} bytes, uncompressed");
+        return;
+    };
+    if !log::log_enabled!(log::Level::Debug) {
+        log::info!("{description} is {size} bytes, compressed with {encoding}");
+        return;
+    }
+    match compressed_size(&response.encode_to_vec(), encoding) {
+        Ok(compressed) => {
+            log::debug!(
+                "{description} is {size} bytes, {compressed} bytes compressed with {encoding}"
+            )
+        }
+        Err(e) => log::warn!("Unable to measure compressed size of {description}: {e}"),
+    }
+}
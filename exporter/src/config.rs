@@ -1,35 +1,481 @@
+use std::time::Duration;
+
 use secstr::SecStr;
 
 use crate::{homeassistant, homeassistant_types};
-use lib::env_params::{get_env_variable, get_optional_env_variable};
+use lib::env_params::{
+    get_env_variable, get_env_variable_with_default, get_optional_env_variable,
+    get_optional_reloadable_env_variable, get_reloadable_env_variable, ConfigParamFromEnv,
+    ReloadableParam,
+};
+use lib::password::{HashedPassword, LockoutPolicy, StoredPassword};
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
-    /// Yeah, just a password. I wondered about using an SSH pub/priv key here, or
-    /// session tokens etc, but it's all overkill and very complicated to set up.
-    pub password: SecStr,
+    /// The displays allowed to connect, each with its own credentials and view of the household. Includes a
+    /// display named "default" that can see everything if `PASSWORD` is set.
+    pub displays: Vec<DisplayConfig>,
+    /// Whether to accept the password itself from displays that predate challenge-response authentication.
+    pub allow_plaintext_password: bool,
+    /// How displays that keep getting the password wrong are slowed down and locked out.
+    pub lockout: LockoutPolicy,
     pub homeassistant: HomeAssistantConfig,
-    pub person_entity_ids: Vec<homeassistant::PersonId>,
+    pub person_entity_ids: Vec<homeassistant::TrackedEntityId>,
     pub privacy_switch_entity_id: Option<homeassistant_types::InputBooleanId>,
     pub photo_directory: std::path::PathBuf,
+    /// How often to check Home Assistant for changes while a display is watching for updates.
+    pub watch_poll_interval: Duration,
+    pub household_name: Option<String>,
+    /// Encodings that responses may be compressed with, if the display also supports them.
+    pub compression: Vec<tonic::codec::CompressionEncoding>,
+    /// If set, serve over TLS rather than plaintext.
+    pub tls: Option<TlsConfig>,
+    /// If set, new displays can be paired rather than configured by hand.
+    pub pairing: Option<PairingConfig>,
+    /// If set, the exporter accepts tokens from `exporter issue-token` as well as the displays' own credentials.
+    pub tokens: Option<TokenConfig>,
+    /// If set, every request for locations is recorded.
+    pub audit: Option<AuditConfig>,
+}
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// Where the audit log is written, rotated into numbered files alongside it.
+    pub directory: std::path::PathBuf,
+    /// The log is rotated once it would grow past this.
+    pub max_file_bytes: u64,
+    /// How many rotated files to keep, as well as the current one.
+    pub max_files: u32,
+}
+#[derive(Debug, Clone)]
+pub struct TokenConfig {
+    pub signing_key: SecStr,
+    /// IDs of tokens that are no longer accepted, reloaded when the file changes if given by path.
+    pub revoked: Option<ReloadableParam<String>>,
+}
+#[derive(Debug, Clone)]
+pub struct PairingConfig {
+    /// Where paired displays are saved.
+    pub directory: std::path::PathBuf,
+    /// `exporter pair` approves pairings through this port, which only listens on localhost.
+    pub admin_port: u16,
+}
+/// PEM-encoded, reloaded when the files change if they were given by path.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The full chain, starting with the exporter's own certificate.
+    pub certificate: ReloadableParam<String>,
+    pub key: ReloadableParam<SecStr>,
+    /// If set, displays may present a client certificate matching this.
+    pub client_certificates: Option<ClientCertificateConfig>,
+}
+/// Client certificates are accepted if they're issued by one of the CAs, or are on the allowlist.
+#[derive(Debug, Clone)]
+pub struct ClientCertificateConfig {
+    /// PEM-encoded.
+    pub ca_certificates: Option<String>,
+    /// As produced by `lib::tls::fingerprint`.
+    pub fingerprints: Vec<String>,
+}
+/// A display that's allowed to connect, and what it can see. A display must present all of the credentials
+/// that are set.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct DisplayConfig {
+    /// Used in logs.
+    pub name: String,
+    /// Yeah, just a password. I wondered about using an SSH pub/priv key here, or
+    /// session tokens etc, but it's all overkill and very complicated to set up.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_secstr")]
+    pub password: Option<SecStr>,
+    /// An argon2 hash of the password from `exporter hash-password`, so the password itself isn't stored.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_password_hash")]
+    pub password_hash: Option<HashedPassword>,
+    /// Any client certificate accepted by `ClientCertificateConfig`.
+    #[serde(default)]
+    pub require_client_certificate: bool,
+    /// A specific client certificate. Added to `ClientCertificateConfig::fingerprints`.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_fingerprint")]
+    pub client_certificate_sha256: Option<String>,
+    /// A client certificate with this subject, e.g. `CN=Kitchen display`, issued by one of
+    /// `ClientCertificateConfig::ca_certificates`.
+    #[serde(default)]
+    pub client_certificate_subject: Option<String>,
+    /// If set, the display can only see these people.
+    #[serde(default)]
+    pub person_entity_ids: Option<Vec<homeassistant::TrackedEntityId>>,
+    /// If set, the display can only see these zones. People in other zones are shown without a location.
+    #[serde(default)]
+    pub zone_entity_ids: Option<Vec<homeassistant::ZoneId>>,
+}
+impl DisplayConfig {
+    pub fn stored_password(&self) -> Option<StoredPassword> {
+        match (&self.password, &self.password_hash) {
+            (_, Some(hash)) => Some(StoredPassword::Hashed(hash.clone())),
+            (Some(password), None) => Some(StoredPassword::Plaintext(password.clone())),
+            (None, None) => None,
+        }
+    }
+
+    pub fn uses_client_certificate(&self) -> bool {
+        self.require_client_certificate
+            || self.client_certificate_sha256.is_some()
+            || self.client_certificate_subject.is_some()
+    }
+
+    pub fn can_see_person(&self, id: &homeassistant::TrackedEntityId) -> bool {
+        self.person_entity_ids
+            .as_ref()
+            .is_none_or(|ids| ids.contains(id))
+    }
+
+    pub fn can_see_zone(&self, id: &homeassistant::ZoneId) -> bool {
+        self.zone_entity_ids
+            .as_ref()
+            .is_none_or(|ids| ids.contains(id))
+    }
 }
 #[derive(Debug, Clone)]
 pub struct HomeAssistantConfig {
     pub endpoint: String,
-    pub access_token: secstr::SecStr,
+    /// Reloaded when the file changes if given by path.
+    pub access_token: ReloadableParam<SecStr>,
+    /// How long to wait for each request to HA, including reading the response.
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Whether to follow changes over HA's WebSocket API, rather than reading every entity for each request.
+    pub websocket: bool,
+    /// How many more times to try reading from HA before giving up on a request.
+    pub retries: u32,
+    /// After this many requests in a row fail, HA isn't tried again until `circuit_breaker_cooldown` has passed.
+    /// Zero means HA is always tried.
+    pub circuit_breaker_failures: u32,
+    pub circuit_breaker_cooldown: Duration,
+    /// While HA can't be read, displays are sent the last snapshot that was read, for up to this long.
+    pub max_staleness: Duration,
 }
 
 pub fn get_config_from_environment_variables() -> Result<Config, String> {
-    Ok(Config {
+    let mut tls = get_tls_config_from_environment_variables()?;
+    let pairing = get_pairing_config_from_environment_variables()?;
+    let tokens = get_token_config_from_environment_variables()?;
+    let displays = get_displays_from_environment_variables(
+        &mut tls,
+        pairing.as_ref(),
+        pairing.is_some() || tokens.is_some(),
+    )?;
+    let config = Config {
         port: get_env_variable("PORT")?,
-        password: get_env_variable("PASSWORD")?,
+        displays,
+        allow_plaintext_password: get_env_variable_with_default("ALLOW_PLAINTEXT_PASSWORD", false)?,
+        lockout: get_lockout_policy_from_environment_variables()?,
         homeassistant: HomeAssistantConfig {
             endpoint: get_env_variable("HOME_ASSISTANT_ENDPOINT")?,
-            access_token: get_env_variable("HOME_ASSISTANT_ACCESS_TOKEN")?,
+            access_token: get_reloadable_env_variable("HOME_ASSISTANT_ACCESS_TOKEN")?,
+            timeout: Duration::from_secs(get_env_variable_with_default(
+                "HOME_ASSISTANT_TIMEOUT_SECONDS",
+                10,
+            )?),
+            connect_timeout: Duration::from_secs(get_env_variable_with_default(
+                "HOME_ASSISTANT_CONNECT_TIMEOUT_SECONDS",
+                5,
+            )?),
+            websocket: get_env_variable_with_default("HOME_ASSISTANT_WEBSOCKET", true)?,
+            retries: get_env_variable_with_default("HOME_ASSISTANT_RETRIES", 2)?,
+            circuit_breaker_failures: get_env_variable_with_default(
+                "HOME_ASSISTANT_CIRCUIT_BREAKER_FAILURES",
+                3,
+            )?,
+            circuit_breaker_cooldown: Duration::from_secs(get_env_variable_with_default(
+                "HOME_ASSISTANT_CIRCUIT_BREAKER_SECONDS",
+                30,
+            )?),
+            max_staleness: Duration::from_secs(get_env_variable_with_default(
+                "HOME_ASSISTANT_MAX_STALENESS_SECONDS",
+                15 * 60,
+            )?),
         },
         person_entity_ids: get_env_variable("PERSON_ENTITY_IDS")?,
         privacy_switch_entity_id: get_optional_env_variable("PRIVACY_SWITCH")?,
         photo_directory: get_env_variable("PHOTO_DIRECTORY")?,
-    })
+        watch_poll_interval: Duration::from_secs(get_env_variable_with_default(
+            "WATCH_POLL_INTERVAL_SECONDS",
+            10,
+        )?),
+        household_name: get_optional_env_variable("HOUSEHOLD_NAME")?,
+        compression: get_compression_from_environment_variables()?,
+        tls,
+        pairing,
+        tokens,
+        audit: get_audit_config_from_environment_variables()?,
+    };
+    Ok(config)
+}
+
+/// `COMPRESSION` can be empty, to turn compression off.
+fn get_compression_from_environment_variables(
+) -> Result<Vec<tonic::codec::CompressionEncoding>, String> {
+    match get_optional_env_variable::<String>("COMPRESSION")? {
+        Some(encodings) if encodings.is_empty() => Ok(vec![]),
+        Some(encodings) => ConfigParamFromEnv::parse(&encodings),
+        None => Ok(vec![
+            tonic::codec::CompressionEncoding::Zstd,
+            tonic::codec::CompressionEncoding::Gzip,
+        ]),
+    }
+}
+
+fn get_lockout_policy_from_environment_variables() -> Result<LockoutPolicy, String> {
+    let default = LockoutPolicy::default();
+    let policy = LockoutPolicy {
+        backoff: Duration::from_secs(get_env_variable_with_default(
+            "AUTH_BACKOFF_SECONDS",
+            default.backoff.as_secs(),
+        )?),
+        max_failures: get_env_variable_with_default("AUTH_MAX_FAILURES", default.max_failures)?,
+        lockout: Duration::from_secs(get_env_variable_with_default(
+            "AUTH_LOCKOUT_SECONDS",
+            default.lockout.as_secs(),
+        )?),
+    };
+    if policy.max_failures == 0 {
+        return Err("AUTH_MAX_FAILURES must be at least 1.".to_string());
+    }
+    Ok(policy)
+}
+
+/// Read `DISPLAYS`, plus the "default" display made from `PASSWORD` and the client certificate config.
+/// Client certificates named in `DISPLAYS` are added to the TLS config so they're accepted.
+fn get_displays_from_environment_variables(
+    tls: &mut Option<TlsConfig>,
+    pairing: Option<&PairingConfig>,
+    // Whether displays can be added some other way, e.g. by pairing.
+    allow_no_displays: bool,
+) -> Result<Vec<DisplayConfig>, String> {
+    let mut displays: Vec<DisplayConfig> = match get_optional_env_variable::<String>("DISPLAYS")? {
+        Some(json) => serde_json::from_str(&json).map_err(|e| format!("Invalid DISPLAYS: {e}"))?,
+        None => vec![],
+    };
+    if let Some(pairing) = pairing {
+        displays.extend(
+            crate::pairing::load_paired_displays(&pairing.directory).map_err(|e| e.to_string())?,
+        );
+    }
+    let password: Option<SecStr> = get_optional_env_variable("PASSWORD")?;
+    let password_hash: Option<HashedPassword> = get_optional_env_variable("PASSWORD_HASH")?;
+    let has_client_certificates = |tls: &Option<TlsConfig>| {
+        tls.as_ref()
+            .is_some_and(|t| t.client_certificates.is_some())
+    };
+
+    // With client certificates configured, the default display also needs one. Without a password, a client
+    // certificate alone is enough, unless `DISPLAYS` gives finer-grained access.
+    if password.is_some()
+        || password_hash.is_some()
+        || (has_client_certificates(tls) && displays.is_empty())
+    {
+        displays.push(DisplayConfig {
+            name: "default".to_string(),
+            password,
+            password_hash,
+            require_client_certificate: has_client_certificates(tls),
+            client_certificate_sha256: None,
+            client_certificate_subject: None,
+            person_entity_ids: None,
+            zone_entity_ids: None,
+        });
+    }
+    if displays.is_empty() && !allow_no_displays {
+        return Err("At least one of PASSWORD, PASSWORD_HASH, DISPLAYS, PAIRING_DIRECTORY, TOKEN_SIGNING_KEY, TLS_CLIENT_CA, or TLS_CLIENT_CERTIFICATE_FINGERPRINTS must be set.".to_string());
+    }
+
+    for display in &displays {
+        if display.password.is_some() && display.password_hash.is_some() {
+            return Err(format!(
+                "Display '{}' has both a password and a password hash, only one can be set.",
+                display.name
+            ));
+        }
+        if display.stored_password().is_none() && !display.uses_client_certificate() {
+            return Err(format!(
+                "Display '{}' needs a password or client certificate.",
+                display.name
+            ));
+        }
+        if let Some(fingerprint) = &display.client_certificate_sha256 {
+            let Some(tls) = tls.as_mut() else {
+                return Err(format!(
+                    "Display '{}' uses a client certificate, which needs TLS_CERTIFICATE and TLS_KEY.",
+                    display.name
+                ));
+            };
+            tls.client_certificates
+                .get_or_insert_with(|| ClientCertificateConfig {
+                    ca_certificates: None,
+                    fingerprints: vec![],
+                })
+                .fingerprints
+                .push(fingerprint.clone());
+        }
+    }
+    let has_client_ca = tls
+        .as_ref()
+        .and_then(|t| t.client_certificates.as_ref())
+        .is_some_and(|c| c.ca_certificates.is_some());
+    for display in &displays {
+        if display.uses_client_certificate() && !has_client_certificates(tls) {
+            return Err(format!(
+                "Display '{}' uses a client certificate, which needs TLS_CLIENT_CA.",
+                display.name
+            ));
+        }
+        // Without a CA, a certificate is only trusted because its fingerprint is pinned, and anyone can make one
+        // with any subject.
+        if display.client_certificate_subject.is_some()
+            && display.client_certificate_sha256.is_none()
+            && !has_client_ca
+        {
+            return Err(format!(
+                "Display '{}' identifies its client certificate by subject, which needs TLS_CLIENT_CA.",
+                display.name
+            ));
+        }
+    }
+
+    // Displays sharing a password can only be told apart by their client certificates.
+    let identified_by_certificate = |d: &DisplayConfig| {
+        d.client_certificate_sha256.is_some() || d.client_certificate_subject.is_some()
+    };
+    for (index, display) in displays.iter().enumerate() {
+        let Some(password) = display.stored_password() else {
+            continue;
+        };
+        let duplicate = displays[..index].iter().find(|other| {
+            other.stored_password().as_ref() == Some(&password)
+                && !(identified_by_certificate(display) && identified_by_certificate(other))
+        });
+        if let Some(other) = duplicate {
+            return Err(format!(
+                "Displays '{}' and '{}' have the same password, so can't be told apart.",
+                other.name, display.name
+            ));
+        }
+    }
+    Ok(displays)
+}
+
+fn deserialize_secstr<'de, D>(deserializer: D) -> Result<Option<SecStr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let password: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(password.map(SecStr::from))
+}
+fn deserialize_password_hash<'de, D>(deserializer: D) -> Result<Option<HashedPassword>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let hash: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    hash.map(|h| HashedPassword::parse(&h).map_err(serde::de::Error::custom))
+        .transpose()
+}
+fn deserialize_fingerprint<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let fingerprint: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    fingerprint
+        .map(|f| lib::tls::parse_fingerprint(&f).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+pub fn get_pairing_config_from_environment_variables() -> Result<Option<PairingConfig>, String> {
+    let Some(directory) = get_optional_env_variable("PAIRING_DIRECTORY")? else {
+        return Ok(None);
+    };
+    let admin_port = match get_optional_env_variable("ADMIN_PORT")? {
+        Some(port) => port,
+        None => get_env_variable::<u16>("PORT")?
+            .checked_add(1)
+            .ok_or("ADMIN_PORT must be set if PORT is 65535.")?,
+    };
+    Ok(Some(PairingConfig {
+        directory,
+        admin_port,
+    }))
+}
+
+pub fn get_token_config_from_environment_variables() -> Result<Option<TokenConfig>, String> {
+    let signing_key: Option<SecStr> = get_optional_env_variable("TOKEN_SIGNING_KEY")?;
+    let revoked = get_optional_reloadable_env_variable("REVOKED_TOKENS")?;
+    let Some(signing_key) = signing_key else {
+        if revoked.is_some() {
+            return Err("REVOKED_TOKENS needs TOKEN_SIGNING_KEY.".to_string());
+        }
+        return Ok(None);
+    };
+    // Anyone that can guess the key can issue themselves a token.
+    if signing_key.unsecure().len() < 32 {
+        return Err("TOKEN_SIGNING_KEY must be at least 32 characters.".to_string());
+    }
+    Ok(Some(TokenConfig {
+        signing_key,
+        revoked,
+    }))
+}
+
+pub fn get_audit_config_from_environment_variables() -> Result<Option<AuditConfig>, String> {
+    let Some(directory) = get_optional_env_variable("AUDIT_LOG_DIRECTORY")? else {
+        return Ok(None);
+    };
+    let max_file_bytes: u64 =
+        get_env_variable_with_default("AUDIT_LOG_MAX_BYTES", 10 * 1024 * 1024)?;
+    if max_file_bytes == 0 {
+        return Err("AUDIT_LOG_MAX_BYTES must be at least 1.".to_string());
+    }
+    Ok(Some(AuditConfig {
+        directory,
+        max_file_bytes,
+        max_files: get_env_variable_with_default("AUDIT_LOG_MAX_FILES", 5)?,
+    }))
+}
+
+pub fn get_tls_config_from_environment_variables() -> Result<Option<TlsConfig>, String> {
+    let client_certificates = get_client_certificate_config_from_environment_variables()?;
+    match (
+        get_optional_reloadable_env_variable("TLS_CERTIFICATE")?,
+        get_optional_reloadable_env_variable("TLS_KEY")?,
+    ) {
+        (Some(certificate), Some(key)) => Ok(Some(TlsConfig {
+            certificate,
+            key,
+            client_certificates,
+        })),
+        (None, None) if client_certificates.is_some() => Err(
+            "Client certificates can only be used with TLS_CERTIFICATE and TLS_KEY.".to_string(),
+        ),
+        (None, None) => Ok(None),
+        _ => Err("TLS_CERTIFICATE and TLS_KEY must be set together.".to_string()),
+    }
+}
+
+fn get_client_certificate_config_from_environment_variables(
+) -> Result<Option<ClientCertificateConfig>, String> {
+    let ca_certificates = get_optional_env_variable("TLS_CLIENT_CA")?;
+    let fingerprints = get_env_variable_with_default::<Vec<String>>(
+        "TLS_CLIENT_CERTIFICATE_FINGERPRINTS",
+        vec![],
+    )?
+    .iter()
+    .map(|f| lib::tls::parse_fingerprint(f))
+    .collect::<Result<Vec<_>, _>>()?;
+    if ca_certificates.is_none() && fingerprints.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(ClientCertificateConfig {
+        ca_certificates,
+        fingerprints,
+    }))
 }
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio_rustls::rustls;
+
+use lib::clock_pb::clock_service_server::ClockServiceServer;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+use tonic_health::server::HealthReporter;
+
+use crate::clock_service::ClockServer;
+use crate::config;
+use crate::homeassistant;
+
+/// How often to check whether Home Assistant is reachable.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keep the `ClockService` health status in line with whether Home Assistant is reachable, since the service
+/// is useless without it.
+pub async fn report_homeassistant_health(
+    reporter: HealthReporter,
+    client: homeassistant::SharedClient,
+) {
+    let mut was_healthy = None;
+    loop {
+        let result = client.get().check_connection().await;
+        let healthy = result.is_ok();
+        if was_healthy != Some(healthy) {
+            match result {
+                Ok(()) => {
+                    log::info!("Home Assistant is reachable");
+                    reporter
+                        .set_serving::<ClockServiceServer<ClockServer>>()
+                        .await;
+                }
+                Err(e) => {
+                    log::warn!("Home Assistant is unreachable: {e}");
+                    reporter
+                        .set_not_serving::<ClockServiceServer<ClockServer>>()
+                        .await;
+                }
+            }
+            was_healthy = Some(healthy);
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+/// Ask the exporter running on this machine whether it's healthy, for Docker's `HEALTHCHECK`.
+pub async fn healthcheck(port: u16, tls: Option<&config::TlsConfig>) -> anyhow::Result<()> {
+    let endpoint = tonic::transport::Channel::from_shared(format!("http://127.0.0.1:{port}"))?;
+    let channel = match tls {
+        // The certificate won't be valid for localhost, so just check it's the one we're configured with.
+        Some(tls) => {
+            let certificates =
+                lib::tls::parse_certificates(&tls.certificate.value).map_err(anyhow::Error::msg)?;
+            let client_config =
+                rustls::ClientConfig::builder_with_provider(lib::tls::crypto_provider())
+                    .with_safe_default_protocol_versions()?
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(lib::tls::PinnedCertificate::new(
+                        &certificates[0],
+                    )))
+                    .with_no_client_auth();
+            lib::tls::connect(endpoint, client_config, "localhost".try_into()?).await?
+        }
+        None => endpoint.connect().await?,
+    };
+    let mut client = HealthClient::new(channel);
+    let service = <ClockServiceServer<ClockServer> as tonic::server::NamedService>::NAME;
+    let response = client
+        .check(HealthCheckRequest {
+            service: service.to_string(),
+        })
+        .await?
+        .into_inner();
+    match response.status() {
+        ServingStatus::Serving => Ok(()),
+        status => anyhow::bail!("{service} is {}", status.as_str_name()),
+    }
+}
@@ -1,5 +1,12 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use std::{collections::HashMap, string::ToString};
 
+use lib::env_params::watch_for_changes;
+use secstr::SecStr;
+
+use crate::config;
+
 // Re-export the types for convenience.
 pub use crate::homeassistant_types::*;
 
@@ -19,26 +26,62 @@ pub enum Error {
     InvalidAccessToken(#[from] std::str::Utf8Error),
     #[error("Invalid data: {0}")]
     InvalidData(String),
+    #[error("Not found in Home Assistant")]
+    NotFound,
+}
+impl Error {
+    /// A description that's safe to send to displays, without URLs or HA's responses. The full error should be
+    /// logged instead.
+    pub fn summary(&self) -> &'static str {
+        match self {
+            Error::NotFound => "Not found in Home Assistant",
+            Error::JsonDecode(..) | Error::InvalidData(_) => "Invalid data from Home Assistant",
+            Error::InvalidHeaderValue(_)
+            | Error::Reqwest(_)
+            | Error::UrlParse(_)
+            | Error::JsonEncode(..)
+            | Error::InvalidAccessToken(_) => "Unable to read from Home Assistant",
+        }
+    }
 }
 
+/// Reported as the entity in error when the list of zones couldn't be read.
+const ALL_ZONES_ENTITY_ID: &str = "zone.*";
+
+/// How often to check whether the access token's file has changed.
+const ACCESS_TOKEN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct Client {
+    /// Keeps connections to HA open between requests.
     client: reqwest::Client,
     server_endpoint: reqwest::Url,
+    access_token: SecStr,
 }
 impl Client {
-    pub fn new(access_token: &secstr::SecStr, endpoint: &str) -> Result<Self, Error> {
+    pub fn new(access_token: &SecStr, config: &config::HomeAssistantConfig) -> Result<Self, Error> {
         let headers = Client::make_headers(access_token)?;
         let client = reqwest::Client::builder()
             .default_headers(headers)
-            .timeout(std::time::Duration::from_secs(10))
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
             .build()?;
         Ok(Client {
             client,
-            server_endpoint: reqwest::Url::parse(endpoint)?,
+            server_endpoint: reqwest::Url::parse(&config.endpoint)?,
+            access_token: access_token.clone(),
         })
     }
 
-    fn make_headers(access_token: &secstr::SecStr) -> Result<reqwest::header::HeaderMap, Error> {
+    pub fn endpoint(&self) -> &reqwest::Url {
+        &self.server_endpoint
+    }
+
+    /// For HA's WebSocket API, which authenticates in a message rather than a header.
+    pub fn access_token(&self) -> &SecStr {
+        &self.access_token
+    }
+
+    fn make_headers(access_token: &SecStr) -> Result<reqwest::header::HeaderMap, Error> {
         let access_token_str = std::str::from_utf8(access_token.unsecure())?;
         let mut auth_header: reqwest::header::HeaderValue =
             format!("Bearer {access_token_str}").parse()?;
@@ -62,12 +105,29 @@ impl Client {
 
     pub async fn get_entity<T: Entity>(&self, id: &T::Id) -> Result<T, Error> {
         // Risk of parameter injection? Nah, no way.
-        let url = self.make_url(&format!("/api/states/{}", &id.to_string()));
+        let url = self.make_url(&format!("/api/states/{}", id.to_string()));
         let response = self.get(&url).await?;
         let body = response.text().await?;
         serde_json::from_str(&body).map_err(|e| Error::JsonDecode(url, e, body))
     }
 
+    /// Device trackers are read as people.
+    pub async fn get_tracked_entity(&self, id: &TrackedEntityId) -> Result<Person, Error> {
+        match id {
+            TrackedEntityId::Person(id) => self.get_entity::<Person>(id).await,
+            TrackedEntityId::DeviceTracker(id) => {
+                self.get_entity::<DeviceTracker>(id).await.map(Person::from)
+            }
+        }
+    }
+
+    /// Every entity's state, which is much quicker than asking for each one in turn.
+    pub async fn get_all_states(&self) -> Result<Vec<serde_json::Value>, Error> {
+        let url = self.make_url("/api/states");
+        let body = self.get(&url).await?.error_for_status()?.text().await?;
+        serde_json::from_str(&body).map_err(|e| Error::JsonDecode(url, e, body))
+    }
+
     pub async fn get_template<T: serde::de::DeserializeOwned>(
         &self,
         template: String,
@@ -99,6 +159,14 @@ impl Client {
         }
     }
 
+    /// Check that HA is reachable and accepts our access token.
+    pub async fn check_connection(&self) -> Result<(), Error> {
+        self.get(&self.make_url("/api/"))
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
     pub async fn get_zone_ids(&self) -> Result<Vec<ZoneId>, Error> {
         // Get all zone IDs
         let template = r#"{{states.zone|list|map(attribute="entity_id")|list|to_json}}"#;
@@ -106,63 +174,308 @@ impl Client {
     }
 }
 
+/// One `Client` for everything that talks to HA, so that connections are reused. Clones share the same client.
+#[derive(Clone)]
+pub struct SharedClient {
+    config: config::HomeAssistantConfig,
+    client: Arc<RwLock<Arc<Client>>>,
+}
+impl SharedClient {
+    pub fn new(config: &config::HomeAssistantConfig) -> Result<Self, Error> {
+        let client = Client::new(&config.access_token.value, config)?;
+        Ok(SharedClient {
+            config: config.clone(),
+            client: Arc::new(RwLock::new(Arc::new(client))),
+        })
+    }
+
+    pub fn get(&self) -> Arc<Client> {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Replace the client with one using a new access token. Requests already made with the old client finish
+    /// with it.
+    pub fn rebuild(&self, access_token: &SecStr) -> Result<(), Error> {
+        let client = Client::new(access_token, &self.config)?;
+        *self.client.write().unwrap() = Arc::new(client);
+        Ok(())
+    }
+}
+
+/// Rebuild the client whenever the access token's file changes, e.g. when the token is replaced. If the new
+/// token can't be used, the old client is kept.
+pub async fn rebuild_when_access_token_changes(client: SharedClient) {
+    let access_token = client.config.access_token.clone();
+    watch_for_changes(access_token, ACCESS_TOKEN_CHECK_INTERVAL, |access_token| {
+        client.rebuild(access_token).map_err(|e| e.to_string())?;
+        log::info!("Loaded new Home Assistant access token");
+        Ok(())
+    })
+    .await
+}
+
+/// An entity that couldn't be read from HA.
+#[derive(Debug)]
+pub struct EntityError {
+    pub entity_id: String,
+    pub error: Error,
+}
+
 #[derive(Debug)]
 pub struct Snapshot {
+    /// Sorted by ID, as are `zones`, so that snapshots of the same state are identical.
     pub people: Vec<Person>,
-    pub zones: std::collections::HashMap<ZoneId, Zone>,
+    pub zones: std::collections::BTreeMap<ZoneId, Zone>,
+    /// Whether the privacy switch is on. Assumed to be if it couldn't be read.
+    pub privacy_enabled: bool,
+    /// Entities that were skipped because they couldn't be read. The rest of the snapshot is still usable.
+    pub errors: Vec<EntityError>,
 }
+impl Snapshot {
+    /// Links people to the zones they're in, leaving out zones that none of `people` are in: the rest would
+    /// give away where people left out of the snapshot are.
+    pub fn new(
+        mut people: std::collections::BTreeMap<TrackedEntityId, Person>,
+        all_zones: impl IntoIterator<Item = (ZoneId, Zone)>,
+        privacy_enabled: bool,
+        mut errors: Vec<EntityError>,
+    ) -> Snapshot {
+        let all_zones: Vec<(ZoneId, Zone)> = all_zones.into_iter().collect();
+        for (zone_id, zone) in &all_zones {
+            let Some(AttributeValue::List(contained_people_ids)) = zone.attributes.get("persons")
+            else {
+                continue;
+            };
+            log::trace!(
+                "Zone {} contains people {:?}",
+                zone.id,
+                contained_people_ids
+            );
 
-pub async fn get_snapshot(client: &Client, person_ids: &Vec<PersonId>) -> Result<Snapshot, Error> {
-    // A naive not-very-async implementation. This could be significantly parallelised, but using e.g.
-    // tokio::task::JoinSet requires fiddling with lifetimes and moved data.
+            // Link any people in this zone.
+            for contained_person_id in contained_people_ids {
+                let AttributeValue::String(id) = contained_person_id else {
+                    log::warn!(
+                        "Got a non-string person ID in zone {}: {:?}",
+                        zone_id,
+                        contained_person_id
+                    );
+                    continue;
+                };
+                let person_id = match PersonId::new(id) {
+                    Ok(person_id) => person_id,
+                    Err(e) => {
+                        log::warn!("Got an invalid person ID in zone {zone_id}: {e}");
+                        errors.push(EntityError {
+                            entity_id: zone_id.to_string(),
+                            error: Error::InvalidData(e),
+                        });
+                        continue;
+                    }
+                };
+                if let Some(person) = people.get_mut(&TrackedEntityId::Person(person_id)) {
+                    person.zone_id = Some(zone_id.clone());
+                }
+            }
+        }
+        // Device trackers aren't listed in zones' `persons`, so are linked to the zone their state names instead.
+        for person in people.values_mut() {
+            if let TrackedEntityId::DeviceTracker(_) = person.id {
+                person.zone_id = all_zones
+                    .iter()
+                    .find(|(_, zone)| zone.is_named_by_state(&person.zone_friendly_name))
+                    .map(|(zone_id, _)| zone_id.clone());
+            }
+        }
+
+        let occupied: std::collections::BTreeSet<&ZoneId> =
+            people.values().filter_map(|p| p.zone_id.as_ref()).collect();
+        let zones = all_zones
+            .into_iter()
+            .filter(|(zone_id, _)| occupied.contains(zone_id))
+            .collect();
 
-    let mut people = HashMap::new();
+        Snapshot {
+            people: people.into_values().collect(),
+            zones,
+            privacy_enabled,
+            errors,
+        }
+    }
+}
+
+pub async fn get_snapshot(
+    client: &Client,
+    person_ids: &[TrackedEntityId],
+    privacy_switch_entity_id: Option<&InputBooleanId>,
+) -> Result<Snapshot, Error> {
+    match client.get_all_states().await {
+        Ok(states) => Ok(snapshot_from_states(
+            states,
+            person_ids,
+            privacy_switch_entity_id,
+        )),
+        Err(e) => {
+            // Only worth it if HA answered but couldn't give every state (e.g. the response was too big): if it
+            // can't be reached at all, asking for each entity would just fail more slowly.
+            let answered = match &e {
+                Error::Reqwest(e) => e.is_status(),
+                Error::JsonDecode(..) => true,
+                _ => false,
+            };
+            if !answered {
+                return Err(e);
+            }
+            log::warn!(
+                "Unable to read every state from HA at once, reading each entity instead: {e}"
+            );
+            get_snapshot_per_entity(client, person_ids, privacy_switch_entity_id).await
+        }
+    }
+}
+
+/// Picks out the people, zones and privacy switch from every entity's state.
+fn snapshot_from_states(
+    states: Vec<serde_json::Value>,
+    person_ids: &[TrackedEntityId],
+    privacy_switch_entity_id: Option<&InputBooleanId>,
+) -> Snapshot {
+    // Sorted, so that errors are always in the same order.
+    let mut states: std::collections::BTreeMap<String, serde_json::Value> = states
+        .into_iter()
+        .filter_map(|state| Some((state.get("entity_id")?.as_str()?.to_string(), state)))
+        .collect();
+    fn parse<T: Entity>(state: Option<serde_json::Value>) -> Result<T, Error> {
+        serde_json::from_value(state.ok_or(Error::NotFound)?)
+            .map_err(|e| Error::InvalidData(e.to_string()))
+    }
+
+    let mut errors = vec![];
+    let mut people = std::collections::BTreeMap::new();
     for person_id in person_ids {
-        let person = client.get_entity::<Person>(person_id).await?;
-        people.insert(person_id, person);
+        let state = states.remove(&person_id.to_string());
+        let person = match person_id {
+            TrackedEntityId::Person(_) => parse::<Person>(state),
+            TrackedEntityId::DeviceTracker(_) => parse::<DeviceTracker>(state).map(Person::from),
+        };
+        match person {
+            Ok(person) => {
+                people.insert(person_id.clone(), person);
+            }
+            Err(error) => {
+                log::warn!("Failed to get {person_id}: {error}");
+                errors.push(EntityError {
+                    entity_id: person_id.to_string(),
+                    error,
+                });
+            }
+        }
     }
 
-    let mut zones = std::collections::HashMap::new();
-    let zone_ids = client.get_zone_ids().await?;
-    log::trace!("All zone ids: {zone_ids:?}");
-    for zone_id in zone_ids {
-        let zone = client.get_entity::<Zone>(&zone_id).await?;
+    let privacy_enabled = match privacy_switch_entity_id {
+        Some(id) => match parse::<InputBoolean>(states.remove(&id.to_string())) {
+            Ok(privacy_input_boolean) => privacy_input_boolean.into(),
+            Err(e) => {
+                log::warn!("Unable to read {id} from HA, assuming privacy is enabled: {e}");
+                true
+            }
+        },
+        None => false,
+    };
 
-        // If there's no people in this zone, then we don't need to transmit any data about it.
-        let Some(AttributeValue::List(contained_people_ids)) = zone.attributes.get("persons")
-        else {
+    let mut zones = vec![];
+    for (entity_id, state) in states {
+        if !entity_id.starts_with(ZoneId::PREFIX) {
             continue;
-        };
-        if contained_people_ids.is_empty() {
+        }
+        let Ok(zone_id) = ZoneId::new(&entity_id) else {
             continue;
+        };
+        match parse::<Zone>(Some(state)) {
+            Ok(zone) => zones.push((zone_id, zone)),
+            Err(error) => {
+                log::warn!("Failed to get {zone_id}: {error}");
+                errors.push(EntityError {
+                    entity_id: zone_id.to_string(),
+                    error,
+                });
+            }
         }
-        log::trace!(
-            "Zone {} contains people {:?}",
-            zone.id,
-            contained_people_ids
-        );
-
-        // Link any people in this zone.
-        for contained_person_id in contained_people_ids {
-            let AttributeValue::String(id) = contained_person_id else {
-                log::warn!(
-                    "Got a non-string person ID in zone {}: {:?}",
-                    &zone_id,
-                    &contained_person_id
-                );
-                continue;
-            };
-            let person_id = PersonId::new(id).map_err(Error::InvalidData)?;
-            if let Some(person) = people.get_mut(&person_id) {
-                person.zone_id = Some(zone_id.clone());
+    }
+
+    Snapshot::new(people, zones, privacy_enabled, errors)
+}
+
+/// For when every state can't be read at once, e.g. because there are too many.
+async fn get_snapshot_per_entity(
+    client: &Client,
+    person_ids: &[TrackedEntityId],
+    privacy_switch_entity_id: Option<&InputBooleanId>,
+) -> Result<Snapshot, Error> {
+    // A naive not-very-async implementation. This could be significantly parallelised, but using e.g.
+    // tokio::task::JoinSet requires fiddling with lifetimes and moved data.
+
+    let mut errors = vec![];
+    let mut people = std::collections::BTreeMap::new();
+    for person_id in person_ids {
+        match client.get_tracked_entity(person_id).await {
+            Ok(person) => {
+                people.insert(person_id.clone(), person);
+            }
+            Err(error) => {
+                log::warn!("Failed to get {person_id}: {error}");
+                errors.push(EntityError {
+                    entity_id: person_id.to_string(),
+                    error,
+                });
             }
         }
+    }
+    // If nobody could be read then HA itself is probably broken, so there's no partial response worth sending.
+    if people.is_empty() && !errors.is_empty() {
+        return Err(errors.remove(0).error);
+    }
 
-        zones.insert(zone_id, zone);
+    let mut zones = vec![];
+    // Without zones, people can still be shown, just not where they are.
+    let zone_ids = match client.get_zone_ids().await {
+        Ok(zone_ids) => zone_ids,
+        Err(error) => {
+            log::warn!("Failed to get zone IDs: {error}");
+            errors.push(EntityError {
+                entity_id: ALL_ZONES_ENTITY_ID.to_string(),
+                error,
+            });
+            vec![]
+        }
+    };
+    log::trace!("All zone ids: {zone_ids:?}");
+    for zone_id in zone_ids {
+        match client.get_entity::<Zone>(&zone_id).await {
+            Ok(zone) => zones.push((zone_id, zone)),
+            Err(error) => {
+                log::warn!("Failed to get {zone_id}: {error}");
+                errors.push(EntityError {
+                    entity_id: zone_id.to_string(),
+                    error,
+                });
+            }
+        }
     }
 
-    Ok(Snapshot {
-        people: people.into_values().collect(),
-        zones,
-    })
+    let privacy_enabled = match privacy_switch_entity_id {
+        Some(id) => match client.get_entity::<InputBoolean>(id).await {
+            Ok(privacy_input_boolean) => privacy_input_boolean.into(),
+            Err(e) => {
+                log::warn!(
+                    "Unable to fetch {} from HA, assuming privacy is enabled: {e}",
+                    id
+                );
+                true
+            }
+        },
+        None => false,
+    };
+
+    Ok(Snapshot::new(people, zones, privacy_enabled, errors))
 }
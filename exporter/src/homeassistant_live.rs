@@ -0,0 +1,496 @@
+//! Keeps the household's state in memory, updated by Home Assistant over its WebSocket API whenever something
+//! changes, so that requests from displays don't each have to read every entity from HA.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::watch;
+use tokio_rustls::rustls;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::homeassistant::{
+    self, DeviceTracker, EntityError, EntityId, InputBoolean, InputBooleanId, Person, SharedClient,
+    TrackedEntityId, Zone, ZoneId,
+};
+
+/// How long to wait before reconnecting after losing the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// HA is pinged this often, and the connection dropped if it hasn't replied by the next ping.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How often to check for zones that have been added or removed without an `entity_registry_updated` event, e.g.
+/// ones defined in YAML, which are only added or removed when HA reloads its config.
+const ZONE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const SUBSCRIBE_TRIGGER_ID: u64 = 1;
+const GET_STATES_ID: u64 = 2;
+const SUBSCRIBE_ENTITY_REGISTRY_ID: u64 = 3;
+
+type Socket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("URL parse error: {0}")]
+    UrlParse(#[from] url::ParseError),
+    #[error("Invalid Home Assistant endpoint: {0}")]
+    InvalidEndpoint(String),
+    #[error("Invalid access token: {0}")]
+    InvalidAccessToken(#[from] std::str::Utf8Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Home Assistant rejected the access token: {0}")]
+    AuthInvalid(String),
+    #[error("Home Assistant request {0} failed: {1}")]
+    RequestFailed(u64, serde_json::Value),
+    #[error("Unexpected message from Home Assistant: {0}")]
+    Unexpected(String),
+    #[error("Home Assistant closed the connection")]
+    Closed,
+    #[error("Home Assistant stopped replying to pings")]
+    Unresponsive,
+    #[error("{0}")]
+    HomeAssistant(#[from] homeassistant::Error),
+    #[error("Zones have changed in Home Assistant")]
+    ZonesChanged,
+}
+
+/// The messages from HA that matter here, see https://developers.home-assistant.io/docs/api/websocket.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Incoming {
+    AuthRequired,
+    AuthOk,
+    AuthInvalid {
+        #[serde(default)]
+        message: String,
+    },
+    Result {
+        id: u64,
+        success: bool,
+        #[serde(default)]
+        result: serde_json::Value,
+        #[serde(default)]
+        error: serde_json::Value,
+    },
+    Event {
+        id: u64,
+        event: serde_json::Value,
+    },
+    Pong,
+    #[serde(other)]
+    Other,
+}
+/// From `subscribe_trigger`, with a state trigger.
+#[derive(Debug, serde::Deserialize)]
+struct TriggerEvent {
+    variables: TriggerVariables,
+}
+#[derive(Debug, serde::Deserialize)]
+struct TriggerVariables {
+    trigger: StateTrigger,
+}
+#[derive(Debug, serde::Deserialize)]
+struct StateTrigger {
+    entity_id: String,
+    /// `None` if the entity was removed.
+    to_state: Option<serde_json::Value>,
+}
+/// An `entity_registry_updated` event.
+#[derive(Debug, serde::Deserialize)]
+struct EntityRegistryEvent {
+    data: EntityRegistryUpdated,
+}
+#[derive(Debug, serde::Deserialize)]
+struct EntityRegistryUpdated {
+    entity_id: String,
+}
+
+/// Which entities are kept, beyond every zone.
+#[derive(Debug, Clone)]
+struct Tracked {
+    person_ids: Vec<TrackedEntityId>,
+    privacy_switch_entity_id: Option<InputBooleanId>,
+}
+impl Tracked {
+    /// Every entity to hear about changes to.
+    fn entity_ids(&self, zone_ids: &[ZoneId]) -> Vec<String> {
+        self.person_ids
+            .iter()
+            .map(ToString::to_string)
+            .chain(
+                self.privacy_switch_entity_id
+                    .iter()
+                    .map(ToString::to_string),
+            )
+            .chain(zone_ids.iter().map(ToString::to_string))
+            .collect()
+    }
+}
+
+/// The entities that snapshots are made from, as of the last update from HA.
+#[derive(Debug, Clone, Default)]
+struct States {
+    people: BTreeMap<TrackedEntityId, Person>,
+    zones: BTreeMap<ZoneId, Zone>,
+    privacy_switch: Option<InputBoolean>,
+    /// Entities whose state couldn't be parsed, with why.
+    invalid: BTreeMap<String, String>,
+}
+impl States {
+    /// Returns whether the entity is one that's kept.
+    fn update(
+        &mut self,
+        tracked: &Tracked,
+        entity_id: &str,
+        state: Option<serde_json::Value>,
+    ) -> bool {
+        self.invalid.remove(entity_id);
+        if let Some(id) = tracked
+            .person_ids
+            .iter()
+            .find(|id| id.to_string() == entity_id)
+        {
+            let person = match id {
+                TrackedEntityId::Person(_) => self.parse::<Person>(entity_id, state),
+                TrackedEntityId::DeviceTracker(_) => self
+                    .parse::<DeviceTracker>(entity_id, state)
+                    .map(Person::from),
+            };
+            match person {
+                Some(person) => self.people.insert(id.clone(), person),
+                None => self.people.remove(id),
+            };
+        } else if entity_id.starts_with(ZoneId::PREFIX) {
+            let Ok(id) = ZoneId::new(entity_id) else {
+                return false;
+            };
+            match self.parse(entity_id, state) {
+                Some(zone) => self.zones.insert(id, zone),
+                None => self.zones.remove(&id),
+            };
+        } else if tracked
+            .privacy_switch_entity_id
+            .as_ref()
+            .is_some_and(|id| id.to_string() == entity_id)
+        {
+            self.privacy_switch = self.parse(entity_id, state);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn parse<T: serde::de::DeserializeOwned>(
+        &mut self,
+        entity_id: &str,
+        state: Option<serde_json::Value>,
+    ) -> Option<T> {
+        match serde_json::from_value(state?) {
+            Ok(entity) => Some(entity),
+            Err(e) => {
+                log::warn!("Unable to parse the state of {entity_id}: {e}");
+                self.invalid.insert(entity_id.to_string(), e.to_string());
+                None
+            }
+        }
+    }
+}
+
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct LiveSnapshot {
+    /// `None` while not connected to HA, so that out of date state isn't served.
+    states: watch::Receiver<Option<Arc<States>>>,
+    privacy_switch_entity_id: Option<InputBooleanId>,
+}
+impl LiveSnapshot {
+    /// Connects to HA in the background, reconnecting whenever the connection is lost.
+    pub fn start(
+        client: SharedClient,
+        person_ids: &[TrackedEntityId],
+        privacy_switch_entity_id: Option<&InputBooleanId>,
+    ) -> Self {
+        let tracked = Tracked {
+            person_ids: person_ids.to_vec(),
+            privacy_switch_entity_id: privacy_switch_entity_id.cloned(),
+        };
+        let (sender, states) = watch::channel(None);
+        tokio::spawn(follow_forever(client, tracked, sender));
+        LiveSnapshot {
+            states,
+            privacy_switch_entity_id: privacy_switch_entity_id.cloned(),
+        }
+    }
+
+    /// The current state of the given people, or `None` if not connected to HA.
+    pub fn snapshot(&self, person_ids: &[TrackedEntityId]) -> Option<homeassistant::Snapshot> {
+        let states = self.states.borrow().clone()?;
+        let mut errors = vec![];
+        let mut people = BTreeMap::new();
+        for id in person_ids {
+            match states.people.get(id) {
+                Some(person) => {
+                    people.insert(id.clone(), person.clone());
+                }
+                None => errors.push(EntityError {
+                    entity_id: id.to_string(),
+                    error: match states.invalid.get(&id.to_string()) {
+                        Some(e) => homeassistant::Error::InvalidData(e.clone()),
+                        None => homeassistant::Error::NotFound,
+                    },
+                }),
+            }
+        }
+        for (entity_id, e) in &states.invalid {
+            if entity_id.starts_with(ZoneId::PREFIX) {
+                errors.push(EntityError {
+                    entity_id: entity_id.clone(),
+                    error: homeassistant::Error::InvalidData(e.clone()),
+                });
+            }
+        }
+
+        let privacy_enabled = match (&self.privacy_switch_entity_id, &states.privacy_switch) {
+            (None, _) => false,
+            (Some(_), Some(privacy_input_boolean)) => privacy_input_boolean.clone().into(),
+            (Some(id), None) => {
+                log::warn!("No state for {id} from HA, assuming privacy is enabled");
+                true
+            }
+        };
+        Some(homeassistant::Snapshot::new(
+            people,
+            states.zones.clone(),
+            privacy_enabled,
+            errors,
+        ))
+    }
+
+    /// Waits until HA reports a change, or the connection is lost or regained.
+    pub async fn changed(&mut self) {
+        if self.states.changed().await.is_err() {
+            // The background task never stops, so this can't happen.
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+async fn follow_forever(
+    client: SharedClient,
+    tracked: Tracked,
+    sender: watch::Sender<Option<Arc<States>>>,
+) {
+    loop {
+        // Picks up a new access token on reconnecting.
+        let Err(e) = follow(&client.get(), &tracked, &sender).await;
+        if let Error::ZonesChanged = e {
+            // The states are still current, the subscription just needs to cover the new zones.
+            log::info!("{e}, resubscribing");
+            continue;
+        }
+        log::warn!(
+            "Lost Home Assistant's WebSocket API, reconnecting in {}s: {e}",
+            RECONNECT_DELAY.as_secs()
+        );
+        sender.send_replace(None);
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// `ws://` or `wss://` from the HTTP endpoint.
+fn websocket_url(endpoint: &url::Url) -> Result<url::Url, Error> {
+    let mut url = endpoint.clone();
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        _ => "ws",
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| Error::InvalidEndpoint(endpoint.to_string()))?;
+    url.set_path("/api/websocket");
+    Ok(url)
+}
+
+/// Trusts the same CAs as `homeassistant::Client`.
+fn tls_connector() -> Result<tokio_tungstenite::Connector, Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    for e in native.errors {
+        log::warn!("Unable to load a CA certificate: {e}");
+    }
+    roots.add_parsable_certificates(native.certs);
+    let config = rustls::ClientConfig::builder_with_provider(lib::tls::crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(tokio_tungstenite::Connector::Rustls(Arc::new(config)))
+}
+
+async fn send(socket: &mut Socket, message: serde_json::Value) -> Result<(), Error> {
+    socket.send(Message::text(message.to_string())).await?;
+    Ok(())
+}
+
+async fn receive(socket: &mut Socket) -> Result<Incoming, Error> {
+    loop {
+        match socket.next().await.ok_or(Error::Closed)?? {
+            Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+            Message::Close(_) => return Err(Error::Closed),
+            // Pings are answered by tungstenite.
+            _ => {}
+        }
+    }
+}
+
+/// Only returns if the connection fails.
+async fn follow(
+    client: &homeassistant::Client,
+    tracked: &Tracked,
+    sender: &watch::Sender<Option<Arc<States>>>,
+) -> Result<std::convert::Infallible, Error> {
+    let url = websocket_url(client.endpoint())?;
+    let connector = match url.scheme() {
+        "wss" => Some(tls_connector()?),
+        _ => None,
+    };
+    let (mut socket, _) =
+        tokio_tungstenite::connect_async_tls_with_config(url.as_str(), None, false, connector)
+            .await?;
+
+    match receive(&mut socket).await? {
+        Incoming::AuthRequired => {}
+        message => return Err(Error::Unexpected(format!("{message:?}"))),
+    }
+    let access_token = std::str::from_utf8(client.access_token().unsecure())?;
+    send(
+        &mut socket,
+        serde_json::json!({"type": "auth", "access_token": access_token}),
+    )
+    .await?;
+    match receive(&mut socket).await? {
+        Incoming::AuthOk => {}
+        Incoming::AuthInvalid { message } => return Err(Error::AuthInvalid(message)),
+        message => return Err(Error::Unexpected(format!("{message:?}"))),
+    }
+
+    // Only the tracked entities and zones, rather than every state change in the house. Subscribed first so
+    // that no changes are missed. Any sent before the states are older than them, so are ignored. Zones that are
+    // moved or resized are covered too, since their attributes change.
+    let mut zone_ids = client.get_zone_ids().await?;
+    zone_ids.sort();
+    let trigger =
+        serde_json::json!({"platform": "state", "entity_id": tracked.entity_ids(&zone_ids)});
+    send(
+        &mut socket,
+        serde_json::json!({"id": SUBSCRIBE_TRIGGER_ID, "type": "subscribe_trigger", "trigger": trigger}),
+    )
+    .await?;
+    send(
+        &mut socket,
+        serde_json::json!({"id": GET_STATES_ID, "type": "get_states"}),
+    )
+    .await?;
+    // Zones that are added or removed later aren't covered by the trigger, so resubscribe when they are. Only
+    // zones made in HA's UI are in the entity registry, so the zones are also checked every
+    // `ZONE_CHECK_INTERVAL`.
+    send(
+        &mut socket,
+        serde_json::json!({"id": SUBSCRIBE_ENTITY_REGISTRY_ID, "type": "subscribe_events", "event_type": "entity_registry_updated"}),
+    )
+    .await?;
+
+    let mut states: Option<States> = None;
+    let mut next_id = SUBSCRIBE_ENTITY_REGISTRY_ID + 1;
+    let mut awaiting_pong = false;
+    let mut ping =
+        tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    let mut zone_check = tokio::time::interval_at(
+        tokio::time::Instant::now() + ZONE_CHECK_INTERVAL,
+        ZONE_CHECK_INTERVAL,
+    );
+    loop {
+        let message = tokio::select! {
+            _ = zone_check.tick() => {
+                match client.get_zone_ids().await {
+                    Ok(mut current) => {
+                        current.sort();
+                        if current != zone_ids {
+                            return Err(Error::ZonesChanged);
+                        }
+                    }
+                    // The subscription still covers the zones it did, so there's no need to reconnect.
+                    Err(e) => log::warn!("Unable to check whether zones have changed: {e}"),
+                }
+                continue;
+            }
+            _ = ping.tick() => {
+                if awaiting_pong {
+                    return Err(Error::Unresponsive);
+                }
+                send(&mut socket, serde_json::json!({"id": next_id, "type": "ping"})).await?;
+                next_id += 1;
+                awaiting_pong = true;
+                continue;
+            }
+            message = receive(&mut socket) => message?,
+        };
+        match message {
+            Incoming::Pong => awaiting_pong = false,
+            Incoming::Result {
+                id,
+                success: false,
+                error,
+                ..
+            } => return Err(Error::RequestFailed(id, error)),
+            Incoming::Result {
+                id: GET_STATES_ID,
+                result,
+                ..
+            } => {
+                let mut loaded = States::default();
+                for state in serde_json::from_value::<Vec<serde_json::Value>>(result)? {
+                    if let Some(entity_id) = state["entity_id"].as_str().map(str::to_string) {
+                        loaded.update(tracked, &entity_id, Some(state));
+                    }
+                }
+                log::info!(
+                    "Following Home Assistant's WebSocket API, with {} people and {} zones",
+                    loaded.people.len(),
+                    loaded.zones.len()
+                );
+                sender.send_replace(Some(Arc::new(loaded.clone())));
+                states = Some(loaded);
+            }
+            Incoming::Event {
+                id: SUBSCRIBE_TRIGGER_ID,
+                event,
+            } => {
+                let Some(states) = &mut states else {
+                    continue;
+                };
+                let trigger = serde_json::from_value::<TriggerEvent>(event)?
+                    .variables
+                    .trigger;
+                if states.update(tracked, &trigger.entity_id, trigger.to_state) {
+                    log::debug!("{} changed", trigger.entity_id);
+                    sender.send_replace(Some(Arc::new(states.clone())));
+                }
+            }
+            Incoming::Event {
+                id: SUBSCRIBE_ENTITY_REGISTRY_ID,
+                event,
+            } => {
+                let updated = serde_json::from_value::<EntityRegistryEvent>(event)?.data;
+                if updated.entity_id.starts_with(ZoneId::PREFIX) {
+                    return Err(Error::ZonesChanged);
+                }
+            }
+            message => log::trace!("Ignoring message from Home Assistant: {message:?}"),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Retries reads from Home Assistant, and stops trying for a while once they keep failing (e.g. while HA
+//! restarts). Until HA can be read again, displays are sent the last snapshot that was read, marked as stale,
+//! while the retries carry on in the background.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use tokio::sync::oneshot;
+
+use crate::config::HomeAssistantConfig;
+use crate::homeassistant::{self, InputBooleanId, SharedClient, Snapshot, TrackedEntityId};
+
+/// How long to wait before the first retry, doubled for each one after.
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// A snapshot of some people, and when it was read.
+type LastGood = HashMap<Vec<TrackedEntityId>, (Instant, Arc<Snapshot>)>;
+
+/// The outcome of the first attempt at reading some people, shared by everyone who asks for them while it's in
+/// flight or while later attempts are retried in the background.
+type FirstAttempt = Shared<BoxFuture<'static, Result<Arc<Snapshot>, String>>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    HomeAssistant(#[from] homeassistant::Error),
+    #[error("Home Assistant has failed {0} times in a row, not trying again for {1}s")]
+    CircuitOpen(u32, u64),
+    #[error("Reading from HA failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+impl Error {
+    /// See `homeassistant::Error::summary`.
+    pub fn summary(&self) -> &'static str {
+        match self {
+            Error::HomeAssistant(e) => e.summary(),
+            Error::CircuitOpen(..) => "Home Assistant is unavailable, try again later",
+            Error::Join(_) => "Unable to read from Home Assistant",
+        }
+    }
+}
+
+pub struct ReadSnapshot {
+    pub snapshot: Arc<Snapshot>,
+    /// Set if HA couldn't be read, to how long ago the snapshot was read.
+    pub stale_age: Option<Duration>,
+}
+
+#[derive(Default)]
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    /// HA isn't tried again until then.
+    open_until: Option<Instant>,
+}
+
+/// Clones share the same circuit breaker and snapshots.
+#[derive(Clone)]
+pub struct SnapshotReader {
+    client: SharedClient,
+    retries: u32,
+    circuit_breaker_failures: u32,
+    circuit_breaker_cooldown: Duration,
+    max_staleness: Duration,
+    circuit_breaker: Arc<Mutex<CircuitBreaker>>,
+    /// Kept for each set of people separately, since displays that can see different people get different
+    /// snapshots.
+    last_good: Arc<Mutex<LastGood>>,
+    /// The sets of people being read in the background.
+    in_flight: Arc<Mutex<HashMap<Vec<TrackedEntityId>, FirstAttempt>>>,
+}
+impl SnapshotReader {
+    pub fn new(client: SharedClient, config: &HomeAssistantConfig) -> Self {
+        SnapshotReader {
+            client,
+            retries: config.retries,
+            circuit_breaker_failures: config.circuit_breaker_failures,
+            circuit_breaker_cooldown: config.circuit_breaker_cooldown,
+            max_staleness: config.max_staleness,
+            circuit_breaker: Default::default(),
+            last_good: Default::default(),
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Keeps a snapshot that was read some other way (e.g. from `LiveSnapshot`) to fall back on later.
+    pub fn remember(&self, person_ids: &[TrackedEntityId], snapshot: Snapshot) -> Arc<Snapshot> {
+        let snapshot = Arc::new(snapshot);
+        self.last_good
+            .lock()
+            .unwrap()
+            .insert(person_ids.to_vec(), (Instant::now(), snapshot.clone()));
+        snapshot
+    }
+
+    /// Falls back to the last snapshot of the same people if HA can't be read first time, unless it's older than
+    /// `HomeAssistantConfig::max_staleness`. Without a snapshot to fall back on, waits for the retries.
+    pub async fn read(
+        &self,
+        person_ids: &[TrackedEntityId],
+        privacy_switch_entity_id: Option<&InputBooleanId>,
+    ) -> Result<ReadSnapshot, Error> {
+        let Some((age, last_good)) = self.last_good(person_ids) else {
+            let snapshot = self
+                .read_with_retries(person_ids, privacy_switch_entity_id, None)
+                .await?;
+            return Ok(ReadSnapshot {
+                snapshot: self.remember(person_ids, snapshot),
+                stale_age: None,
+            });
+        };
+
+        let error = match self
+            .read_in_background(person_ids, privacy_switch_entity_id)
+            .await
+        {
+            Ok(snapshot) => {
+                return Ok(ReadSnapshot {
+                    snapshot,
+                    stale_age: None,
+                })
+            }
+            Err(e) => e,
+        };
+        log::warn!(
+            "Unable to read from HA, using the snapshot from {}s ago: {error}",
+            age.as_secs()
+        );
+        Ok(ReadSnapshot {
+            snapshot: last_good,
+            stale_age: Some(age),
+        })
+    }
+
+    /// The last snapshot of these people and how old it is, unless it's too old to use.
+    fn last_good(&self, person_ids: &[TrackedEntityId]) -> Option<(Duration, Arc<Snapshot>)> {
+        let mut last_good = self.last_good.lock().unwrap();
+        let (read_at, snapshot) = last_good.get(person_ids)?;
+        let age = read_at.elapsed();
+        if age > self.max_staleness {
+            log::warn!(
+                "The last snapshot from HA is too old to fall back on ({}s)",
+                age.as_secs()
+            );
+            last_good.remove(person_ids);
+            return None;
+        }
+        Some((age, snapshot.clone()))
+    }
+
+    /// Reads with retries in a background task, but only waits for the first attempt. If that fails, the task
+    /// keeps retrying and remembers the snapshot once it succeeds, so later reads get it. Reads of the same people
+    /// while the task is running share its first attempt rather than starting another.
+    async fn read_in_background(
+        &self,
+        person_ids: &[TrackedEntityId],
+        privacy_switch_entity_id: Option<&InputBooleanId>,
+    ) -> Result<Arc<Snapshot>, String> {
+        let first_attempt = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(person_ids.to_vec())
+            .or_insert_with(|| self.start_reading(person_ids, privacy_switch_entity_id))
+            .clone();
+        first_attempt.await
+    }
+
+    /// Must be called with `in_flight` locked, so the task can't finish and remove itself before it's added.
+    fn start_reading(
+        &self,
+        person_ids: &[TrackedEntityId],
+        privacy_switch_entity_id: Option<&InputBooleanId>,
+    ) -> FirstAttempt {
+        let (first_failure_tx, first_failure_rx) = oneshot::channel();
+        let reader = self.clone();
+        let person_ids = person_ids.to_vec();
+        let privacy_switch_entity_id = privacy_switch_entity_id.cloned();
+        let mut task = tokio::spawn(async move {
+            let result = reader
+                .read_with_retries(
+                    &person_ids,
+                    privacy_switch_entity_id.as_ref(),
+                    Some(first_failure_tx),
+                )
+                .await
+                .map(|snapshot| reader.remember(&person_ids, snapshot));
+            reader.in_flight.lock().unwrap().remove(&person_ids);
+            result
+        });
+
+        async move {
+            tokio::select! {
+                // If the first attempt succeeds, this is never sent, so wait for the task instead.
+                Ok(error) = first_failure_rx => Err(format!("{error}, retrying in the background")),
+                result = &mut task => match result {
+                    Ok(result) => result.map_err(|e| e.to_string()),
+                    Err(e) => Err(Error::from(e).to_string()),
+                },
+            }
+        }
+        .boxed()
+        .shared()
+    }
+
+    async fn read_with_retries(
+        &self,
+        person_ids: &[TrackedEntityId],
+        privacy_switch_entity_id: Option<&InputBooleanId>,
+        mut first_failure: Option<oneshot::Sender<homeassistant::Error>>,
+    ) -> Result<Snapshot, Error> {
+        self.check_circuit_breaker()?;
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            match homeassistant::get_snapshot(
+                &self.client.get(),
+                person_ids,
+                privacy_switch_entity_id,
+            )
+            .await
+            {
+                Ok(snapshot) => {
+                    self.succeeded();
+                    return Ok(snapshot);
+                }
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Failed to get snapshot from HA, retrying in {delay:?} ({attempt}/{}): {e}",
+                        self.retries
+                    );
+                    if let Some(first_failure) = first_failure.take() {
+                        let _ = first_failure.send(e);
+                    }
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    log::error!("Failed to get snapshot from HA: {e}");
+                    self.failed();
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    /// Once the cooldown has passed, lets one request through to see whether HA has recovered, holding the
+    /// rest back for another cooldown in case it hasn't.
+    fn check_circuit_breaker(&self) -> Result<(), Error> {
+        let mut breaker = self.circuit_breaker.lock().unwrap();
+        let Some(open_until) = breaker.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < open_until {
+            return Err(Error::CircuitOpen(
+                breaker.consecutive_failures,
+                (open_until - now).as_secs(),
+            ));
+        }
+        breaker.open_until = Some(now + self.circuit_breaker_cooldown);
+        Ok(())
+    }
+
+    fn succeeded(&self) {
+        let mut breaker = self.circuit_breaker.lock().unwrap();
+        if breaker.open_until.is_some() {
+            log::info!("Home Assistant has recovered");
+        }
+        *breaker = CircuitBreaker::default();
+    }
+
+    fn failed(&self) {
+        let mut breaker = self.circuit_breaker.lock().unwrap();
+        breaker.consecutive_failures += 1;
+        if self.circuit_breaker_failures > 0
+            && breaker.consecutive_failures >= self.circuit_breaker_failures
+        {
+            log::warn!(
+                "Home Assistant has failed {} times in a row, not trying again for {:?}",
+                breaker.consecutive_failures,
+                self.circuit_breaker_cooldown
+            );
+            breaker.open_until = Some(Instant::now() + self.circuit_breaker_cooldown);
+        }
+    }
+}
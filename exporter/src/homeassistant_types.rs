@@ -38,10 +38,18 @@ impl<'de, const P: PrefixType> serde::Deserialize<'de> for EntityIdImpl<P> {
     where
         D: serde::Deserializer<'de>,
     {
-        let s: &str = serde::Deserialize::deserialize(deserializer)?;
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
         EntityIdImpl::new(s).map_err(serde::de::Error::custom)
     }
 }
+impl<const P: PrefixType> serde::Serialize for EntityIdImpl<P> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
 
 impl<const P: PrefixType> env_params::ConfigParamFromEnv for EntityIdImpl<P> {
     fn parse(val: &str) -> Result<Self, String> {
@@ -52,6 +60,56 @@ impl<const P: PrefixType> env_params::ConfigParamFromEnv for EntityIdImpl<P> {
 pub type PersonId = EntityIdImpl<"person.">;
 pub type ZoneId = EntityIdImpl<"zone.">;
 pub type InputBooleanId = EntityIdImpl<"input_boolean.">;
+pub type DeviceTrackerId = EntityIdImpl<"device_tracker.">;
+
+/// An entity that a member of the household is tracked by: their person entity, or a device tracker for people
+/// without one. Either is shown to displays as a person.
+#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone)]
+pub enum TrackedEntityId {
+    Person(PersonId),
+    DeviceTracker(DeviceTrackerId),
+}
+impl TrackedEntityId {
+    /// IDs without a prefix are taken to be people, as they were before device trackers were supported.
+    pub fn new<S: ToString>(value: S) -> Result<Self, String> {
+        let value = value.to_string();
+        if value.starts_with(DeviceTrackerId::PREFIX) {
+            Ok(TrackedEntityId::DeviceTracker(DeviceTrackerId::new(value)?))
+        } else {
+            Ok(TrackedEntityId::Person(PersonId::new(value)?))
+        }
+    }
+}
+impl std::fmt::Display for TrackedEntityId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackedEntityId::Person(id) => id.fmt(f),
+            TrackedEntityId::DeviceTracker(id) => id.fmt(f),
+        }
+    }
+}
+impl<'de> serde::Deserialize<'de> for TrackedEntityId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
+        TrackedEntityId::new(s).map_err(serde::de::Error::custom)
+    }
+}
+impl serde::Serialize for TrackedEntityId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+impl env_params::ConfigParamFromEnv for TrackedEntityId {
+    fn parse(val: &str) -> Result<Self, String> {
+        TrackedEntityId::new(val)
+    }
+}
 
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(untagged)]
@@ -71,24 +129,50 @@ pub trait Entity: for<'a> serde::Deserialize<'a> {
     type Id: std::string::ToString;
 }
 
+/// Also made from a `DeviceTracker`, since both are shown to displays as people.
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct Person {
     #[serde(rename = "entity_id")]
-    pub id: PersonId,
+    pub id: TrackedEntityId,
 
     /// The state of a person entity is the friendly name for the zone they're in.
     #[serde(rename = "state")]
     pub zone_friendly_name: String,
 
-    /// The ID of the zone the perso n is in. This can't be gleaned from the entity state,
-    /// it should be filled in by looking at the zone entities (which include person IDs).
+    /// The ID of the zone the person is in. This can't be gleaned from the entity state,
+    /// it should be filled in by looking at the zone entities (which include person IDs, but not device
+    /// trackers).
     #[serde(skip)]
     pub zone_id: Option<ZoneId>,
 
+    /// When the state (so the zone) last changed.
+    #[serde(default)]
+    pub last_changed: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the state or any attribute (e.g. GPS coordinates) last changed.
+    #[serde(default)]
+    pub last_updated: Option<chrono::DateTime<chrono::Utc>>,
+
     #[serde(default)]
     pub attributes: AttributeMap,
 }
 impl Person {
+    pub fn get_friendly_name(&self) -> Option<String> {
+        match self.attributes.get("friendly_name") {
+            Some(AttributeValue::String(s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    /// The person's state, tidied up for display. HA uses the zone's friendly name as the state except for
+    /// the home zone and when the person isn't in any zone.
+    pub fn get_zone_display_name(&self) -> String {
+        match self.zone_friendly_name.as_str() {
+            "home" => "Home".to_string(),
+            "not_home" => "Away".to_string(),
+            name => name.to_string(),
+        }
+    }
+
     pub fn get_entity_picture_path(&self) -> Option<String> {
         match self.attributes.get("entity_picture") {
             Some(AttributeValue::String(s)) => Some(s.clone()),
@@ -99,6 +183,40 @@ impl Person {
 impl Entity for Person {
     type Id = PersonId;
 }
+
+/// E.g. a car's GPS tracker, or a router noticing a phone on the network.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct DeviceTracker {
+    #[serde(rename = "entity_id")]
+    pub id: DeviceTrackerId,
+
+    /// Like a person's: the friendly name of the zone the device is in, `home`, or `not_home`.
+    #[serde(rename = "state")]
+    pub zone_friendly_name: String,
+
+    #[serde(default)]
+    pub last_changed: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub last_updated: Option<chrono::DateTime<chrono::Utc>>,
+
+    #[serde(default)]
+    pub attributes: AttributeMap,
+}
+impl From<DeviceTracker> for Person {
+    fn from(tracker: DeviceTracker) -> Self {
+        Person {
+            id: TrackedEntityId::DeviceTracker(tracker.id),
+            zone_friendly_name: tracker.zone_friendly_name,
+            zone_id: None,
+            last_changed: tracker.last_changed,
+            last_updated: tracker.last_updated,
+            attributes: tracker.attributes,
+        }
+    }
+}
+impl Entity for DeviceTracker {
+    type Id = DeviceTrackerId;
+}
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct Zone {
     #[serde(rename = "entity_id")]
@@ -114,6 +232,16 @@ impl Zone {
             _ => None,
         }
     }
+
+    /// Whether a device tracker with this state is in the zone. HA uses `home` for the home zone, and the
+    /// friendly name for the rest.
+    pub fn is_named_by_state(&self, state: &str) -> bool {
+        if self.id.suffix == "home" {
+            state == "home"
+        } else {
+            self.get_friendly_name().is_some_and(|name| name == state)
+        }
+    }
 }
 impl Entity for Zone {
     type Id = ZoneId;
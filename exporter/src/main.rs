@@ -2,18 +2,92 @@
 #![feature(adt_const_params)]
 #![feature(unsized_const_params)]
 
+use std::io::IsTerminal;
 use std::net::Ipv4Addr;
 
+mod audit;
+mod auth_service;
+mod authentication;
 mod clock_service;
+mod compression;
 mod config;
+mod health;
 mod homeassistant;
+mod homeassistant_live;
+mod homeassistant_retry;
 mod homeassistant_types;
+mod pairing;
 mod photo_manager;
+mod photo_resizer;
+mod photo_store;
+mod tls;
+mod tokens;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 5)]
 async fn main() {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
 
+    if std::env::args().nth(1).as_deref() == Some("healthcheck") {
+        let port = lib::env_params::get_env_variable("PORT").unwrap();
+        let tls = config::get_tls_config_from_environment_variables().unwrap();
+        if let Err(e) = health::healthcheck(port, tls.as_ref()).await {
+            log::error!("Unhealthy: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("pair") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        let [code, name] = args.as_slice() else {
+            log::error!("Usage: exporter pair <code shown on the display> <name for the display>");
+            std::process::exit(2);
+        };
+        let Some(pairing) = config::get_pairing_config_from_environment_variables().unwrap() else {
+            log::error!("Pairing isn't enabled, set PAIRING_DIRECTORY");
+            std::process::exit(1);
+        };
+        match pairing::approve(&pairing, code, name).await {
+            Ok(()) => log::info!("Paired display '{name}'"),
+            Err(e) => {
+                log::error!("Unable to pair: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("issue-token") {
+        match issue_token(std::env::args().skip(2).collect()) {
+            Ok(token) => println!("{token}"),
+            Err(e) => {
+                log::error!("Unable to issue token: {e}");
+                log::error!("Usage: exporter issue-token <name> [--valid-for-days <days>] [--person <person or device tracker entity ID>]... [--zone <zone entity ID>]...");
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("audit") {
+        match summarise_audit_log(std::env::args().skip(2).collect()) {
+            Ok(summary) => print!("{summary}"),
+            Err(e) => {
+                log::error!("Unable to summarise the audit log: {e}");
+                log::error!("Usage: exporter audit [--days <days>]");
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("hash-password") {
+        match hash_password() {
+            Ok(hash) => println!("{hash}"),
+            Err(e) => {
+                log::error!("Unable to hash password: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let config = config::get_config_from_environment_variables().unwrap();
     log::info!("Read config: {:?}", config);
 
@@ -23,18 +97,187 @@ async fn main() {
     log::info!("Server halted with error: {status:?}\nRestarting after delay.");
 }
 
+/// Make a token for `TOKEN_SIGNING_KEY`, with access limited by the arguments.
+fn issue_token(args: Vec<String>) -> Result<String, String> {
+    let mut args = args.into_iter();
+    let name = args.next().ok_or("A name for the token is required.")?;
+    let mut claims = tokens::TokenClaims::new(name)?;
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or(format!("{flag} needs a value."))?;
+        match flag.as_str() {
+            "--valid-for-days" => {
+                let days: u32 = value.parse().map_err(|e| format!("Invalid days: {e}"))?;
+                claims.expires_unix_seconds =
+                    Some(chrono::Utc::now().timestamp() + i64::from(days) * 24 * 60 * 60);
+            }
+            "--person" => claims
+                .person_entity_ids
+                .get_or_insert_with(Vec::new)
+                .push(homeassistant::TrackedEntityId::new(value)?),
+            "--zone" => claims.zone_entity_ids.get_or_insert_with(Vec::new).push(
+                <homeassistant::ZoneId as homeassistant::EntityId>::new(value)?,
+            ),
+            _ => return Err(format!("Unknown option {flag}.")),
+        }
+    }
+
+    let config = config::get_token_config_from_environment_variables()?
+        .ok_or("Tokens aren't enabled, set TOKEN_SIGNING_KEY.")?;
+    let token = tokens::Tokens::new(&config).issue(&claims)?;
+    log::info!(
+        "Issued token {} for '{}'{}, revoke it by adding its ID to REVOKED_TOKENS",
+        claims.id,
+        claims.name,
+        claims
+            .expires_unix_seconds
+            .and_then(|e| chrono::DateTime::from_timestamp(e, 0))
+            .map(|e| format!(" until {e}"))
+            .unwrap_or_default(),
+    );
+    Ok(token)
+}
+
+/// Summarise the audit log in `AUDIT_LOG_DIRECTORY`, optionally only the last few days of it.
+fn summarise_audit_log(args: Vec<String>) -> Result<String, String> {
+    let since = match args.as_slice() {
+        [] => None,
+        [flag, days] if flag == "--days" => {
+            let days: u32 = days.parse().map_err(|e| format!("Invalid days: {e}"))?;
+            Some(chrono::Utc::now() - chrono::Duration::days(i64::from(days)))
+        }
+        _ => return Err("Unknown arguments.".to_string()),
+    };
+    let config = config::get_audit_config_from_environment_variables()?
+        .ok_or("Audit logging isn't enabled, set AUDIT_LOG_DIRECTORY.")?;
+    audit::summarise(&config, since).map_err(|e| e.to_string())
+}
+
+/// Hash a password typed at the terminal (or piped to stdin), for `PASSWORD_HASH`.
+fn hash_password() -> Result<String, String> {
+    let password = if std::io::stdin().is_terminal() {
+        let password = rpassword::prompt_password("Password: ").map_err(|e| e.to_string())?;
+        if rpassword::prompt_password("Repeat password: ").map_err(|e| e.to_string())? != password {
+            return Err("The passwords don't match.".to_string());
+        }
+        password
+    } else {
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if password.is_empty() {
+        return Err("The password can't be empty.".to_string());
+    }
+    lib::password::hash_password(&secstr::SecStr::from(password))
+}
+
 async fn run(config: &config::Config, addr: std::net::SocketAddr) -> anyhow::Result<()> {
+    let passwords: Vec<_> = config
+        .displays
+        .iter()
+        .filter_map(config::DisplayConfig::stored_password)
+        .collect();
+    let challenges = lib::password::Challenges::new(&passwords).map_err(anyhow::Error::msg)?;
+    let tokens = config.tokens.as_ref().map(tokens::Tokens::new);
+    if let Some(revoked) = config.tokens.as_ref().and_then(|t| t.revoked.clone()) {
+        tokio::spawn(tokens::reload_revocations_when_changed(
+            tokens.clone().unwrap(),
+            revoked,
+        ));
+    }
+    let authenticate = authentication::Authenticate::new(
+        &config.displays,
+        challenges.clone(),
+        config.allow_plaintext_password,
+        config.lockout.clone(),
+        tokens,
+    );
+    let audit = config
+        .audit
+        .as_ref()
+        .map(audit::AuditLog::open)
+        .transpose()?;
+    let homeassistant = homeassistant::SharedClient::new(&config.homeassistant)?;
+    if config.homeassistant.access_token.path.is_some() {
+        tokio::spawn(homeassistant::rebuild_when_access_token_changes(
+            homeassistant.clone(),
+        ));
+    }
+    let live = config.homeassistant.websocket.then(|| {
+        homeassistant_live::LiveSnapshot::start(
+            homeassistant.clone(),
+            &config.person_entity_ids,
+            config.privacy_switch_entity_id.as_ref(),
+        )
+    });
     let clock_service = clock_service::ClockServer::make_server(
-        config.password.clone(),
-        config.homeassistant.clone(),
-        config.person_entity_ids.clone(),
-        &config.privacy_switch_entity_id,
-        photo_manager::PhotoManager::new(config.photo_directory.clone()),
+        config,
+        authenticate.clone(),
+        audit,
+        homeassistant.clone(),
+        live,
     );
-    let clock_server = tonic::transport::Server::builder().add_service(clock_service);
+    let auth_service = lib::clock_pb::auth_service_server::AuthServiceServer::new(
+        auth_service::AuthServer::new(challenges),
+    );
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(health::report_homeassistant_health(
+        health_reporter,
+        homeassistant,
+    ));
+
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(lib::clock_pb::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build_v1()?;
+
+    let pairing_service = match &config.pairing {
+        Some(pairing_config) => {
+            let pairing = pairing::PairingServer::new(authenticate, pairing_config.clone());
+            // Only reachable from this machine, so anyone that can approve a pairing could already read the
+            // config.
+            let admin_addr = std::net::SocketAddr::new(
+                std::net::IpAddr::V4(Ipv4Addr::LOCALHOST),
+                pairing_config.admin_port,
+            );
+            let admin_server = tonic::transport::Server::builder().add_service(
+                lib::clock_pb::admin_service_server::AdminServiceServer::new(pairing.clone()),
+            );
+            tokio::spawn(async move {
+                log::info!("Starting AdminService on {admin_addr}");
+                if let Err(e) = admin_server.serve(admin_addr).await {
+                    log::error!("AdminService stopped, displays can't be paired: {e}");
+                }
+            });
+            Some(lib::clock_pb::pairing_service_server::PairingServiceServer::new(pairing))
+        }
+        None => None,
+    };
+
+    // The auth, pairing, health and reflection services don't need the password: they reveal nothing about
+    // the household.
+    let clock_server = tonic::transport::Server::builder()
+        .add_service(clock_service)
+        .add_service(auth_service)
+        .add_optional_service(pairing_service)
+        .add_service(health_service)
+        .add_service(reflection_service);
 
-    log::info!("Starting ClockServer on {addr}");
-    let status = clock_server.serve(addr).await;
+    let status = match &config.tls {
+        Some(tls_config) => {
+            log::info!("Starting ClockServer on {addr} with TLS");
+            clock_server
+                .serve_with_incoming(tls::incoming(addr, tls_config).await?)
+                .await
+        }
+        None => {
+            log::info!("Starting ClockServer on {addr}");
+            clock_server.serve(addr).await
+        }
+    };
     log::info!("ClockServer stopped with status: {status:?}");
     status?;
 
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use aws_lc_rs::rand::SecureRandom;
+use lib::clock_pb::admin_service_client::AdminServiceClient;
+use lib::clock_pb::admin_service_server::AdminService;
+use lib::clock_pb::pairing_service_server::PairingService;
+use lib::clock_pb::{
+    ApprovePairingRequest, ApprovePairingResponse, FinishPairingRequest, FinishPairingResponse,
+    StartPairingRequest, StartPairingResponse,
+};
+use lib::pairing::{KeyPair, PAIRING_LIFETIME};
+use secstr::SecStr;
+
+use crate::authentication::Authenticate;
+use crate::config::{self, DisplayConfig};
+
+/// Within `PAIRING_DIRECTORY`. In the same format as `DISPLAYS`, so displays can be edited, e.g. to limit who
+/// they can see.
+const PAIRED_DISPLAYS_FILE_NAME: &str = "paired_displays.json";
+/// Pairing doesn't need authenticating, so only keep track of this many at once.
+const MAX_PENDING_PAIRINGS: usize = 16;
+/// So one machine can't hold up every other display's pairing.
+const MAX_PENDING_PAIRINGS_PER_ADDRESS: usize = 2;
+/// Displays reveal their key straight after starting, so pairings that haven't can be dropped well before
+/// `PAIRING_LIFETIME`.
+const UNREVEALED_PAIRING_LIFETIME: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid paired displays file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub fn load_paired_displays(directory: &Path) -> Result<Vec<DisplayConfig>, Error> {
+    match std::fs::read(directory.join(PAIRED_DISPLAYS_FILE_NAME)) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds to any displays already in the file, keeping any edits to them.
+fn save_paired_display(directory: &Path, name: &str, password: &SecStr) -> Result<(), Error> {
+    let path = directory.join(PAIRED_DISPLAYS_FILE_NAME);
+    let mut displays: Vec<serde_json::Value> = match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    displays.push(serde_json::json!({
+        "name": name,
+        "password": String::from_utf8_lossy(password.unsecure()),
+    }));
+
+    // Written alongside then renamed, so a crash can't leave a half-written file.
+    std::fs::create_dir_all(directory)?;
+    let temporary_path = path.with_extension("json.tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary_path)?;
+    file.write_all(&serde_json::to_vec_pretty(&displays)?)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary_path, &path)?;
+    Ok(())
+}
+
+struct PendingPairing {
+    started_at: Instant,
+    address: Option<IpAddr>,
+    display_key_commitment: Vec<u8>,
+    exporter_public_key: Vec<u8>,
+    /// Used up once the display reveals its key.
+    exporter_keys: Option<KeyPair>,
+    revealed: Option<RevealedPairing>,
+    approved: bool,
+}
+impl PendingPairing {
+    fn expired(&self) -> bool {
+        let lifetime = match self.revealed {
+            Some(_) => PAIRING_LIFETIME,
+            None => UNREVEALED_PAIRING_LIFETIME,
+        };
+        self.started_at.elapsed() >= lifetime
+    }
+}
+struct RevealedPairing {
+    display_public_key: Vec<u8>,
+    code: String,
+    password: SecStr,
+}
+
+/// Serves `PairingService` to displays, and `AdminService` to `exporter pair`. Clones share the same pairings.
+#[derive(Clone)]
+pub struct PairingServer {
+    pending: Arc<Mutex<HashMap<String, PendingPairing>>>,
+    authenticate: Authenticate,
+    config: config::PairingConfig,
+}
+impl PairingServer {
+    pub fn new(authenticate: Authenticate, config: config::PairingConfig) -> Self {
+        PairingServer {
+            pending: Arc::default(),
+            authenticate,
+            config,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl PairingService for PairingServer {
+    async fn start_pairing(
+        &self,
+        request: tonic::Request<StartPairingRequest>,
+    ) -> tonic::Result<tonic::Response<StartPairingResponse>> {
+        let address = request.remote_addr().map(|a| a.ip());
+        let commitment = request.into_inner().display_key_commitment;
+        if commitment.len() != 32 {
+            return Err(tonic::Status::invalid_argument("Invalid key commitment."));
+        }
+        let mut id = [0u8; 16];
+        aws_lc_rs::rand::SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| tonic::Status::internal("Failed to generate a pairing ID."))?;
+        let id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+        let keys = KeyPair::generate().map_err(tonic::Status::internal)?;
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| !p.expired());
+        let from_address = pending.values().filter(|p| p.address == address).count();
+        if pending.len() >= MAX_PENDING_PAIRINGS
+            || (address.is_some() && from_address >= MAX_PENDING_PAIRINGS_PER_ADDRESS)
+        {
+            return Err(tonic::Status::resource_exhausted(
+                "Too many displays are pairing, try again later.",
+            ));
+        }
+        let response = StartPairingResponse {
+            pairing_id: id.clone(),
+            exporter_public_key: keys.public_key.clone(),
+            lifetime_seconds: PAIRING_LIFETIME.as_secs() as u32,
+        };
+        pending.insert(
+            id,
+            PendingPairing {
+                started_at: Instant::now(),
+                address,
+                display_key_commitment: commitment,
+                exporter_public_key: keys.public_key.clone(),
+                exporter_keys: Some(keys),
+                revealed: None,
+                approved: false,
+            },
+        );
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn finish_pairing(
+        &self,
+        request: tonic::Request<FinishPairingRequest>,
+    ) -> tonic::Result<tonic::Response<FinishPairingResponse>> {
+        let request = request.into_inner();
+        let mut pending = self.pending.lock().unwrap();
+        let pairing = pending
+            .get_mut(&request.pairing_id)
+            .filter(|p| !p.expired())
+            .ok_or(tonic::Status::not_found("Unknown or expired pairing."))?;
+
+        match &pairing.revealed {
+            Some(revealed) if revealed.display_public_key != request.display_public_key => {
+                return Err(tonic::Status::invalid_argument(
+                    "Display key doesn't match the one already sent.",
+                ));
+            }
+            Some(_) => {}
+            None => {
+                if lib::pairing::commitment(&request.display_public_key)
+                    != pairing.display_key_commitment
+                {
+                    return Err(tonic::Status::invalid_argument(
+                        "Display key doesn't match its commitment.",
+                    ));
+                }
+                let Some(keys) = pairing.exporter_keys.take() else {
+                    return Err(tonic::Status::internal("Pairing keys already used."));
+                };
+                let password = keys
+                    .agree(&request.display_public_key, &pairing.exporter_public_key)
+                    .map_err(tonic::Status::invalid_argument)?;
+                let code =
+                    lib::pairing::code(&request.display_public_key, &pairing.exporter_public_key);
+                // The code isn't logged: anyone who can read the logs could then approve their own display.
+                log::info!(
+                    "A display is waiting to pair, approve it with `exporter pair <code shown on the display> <name>`"
+                );
+                pairing.revealed = Some(RevealedPairing {
+                    display_public_key: request.display_public_key,
+                    code,
+                    password,
+                });
+            }
+        }
+        Ok(tonic::Response::new(FinishPairingResponse {
+            approved: pairing.approved,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for PairingServer {
+    async fn approve_pairing(
+        &self,
+        request: tonic::Request<ApprovePairingRequest>,
+    ) -> tonic::Result<tonic::Response<ApprovePairingResponse>> {
+        let request = request.into_inner();
+        let code = lib::pairing::normalise_code(&request.code);
+        let name = request.display_name.trim();
+        if name.is_empty() {
+            return Err(tonic::Status::invalid_argument("The display needs a name."));
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        let pairing = pending
+            .values_mut()
+            .filter(|p| !p.expired() && !p.approved)
+            .find(|p| p.revealed.as_ref().is_some_and(|r| r.code == code))
+            .ok_or(tonic::Status::not_found(format!(
+                "No display is waiting to pair with code {code}."
+            )))?;
+        let Some(revealed) = &pairing.revealed else {
+            return Err(tonic::Status::internal("Pairing has no code."));
+        };
+
+        save_paired_display(&self.config.directory, name, &revealed.password).map_err(|e| {
+            log::error!("Unable to save paired display '{name}': {e}");
+            tonic::Status::internal(e.to_string())
+        })?;
+        self.authenticate.add_display(DisplayConfig {
+            name: name.to_string(),
+            password: Some(revealed.password.clone()),
+            password_hash: None,
+            require_client_certificate: false,
+            client_certificate_sha256: None,
+            client_certificate_subject: None,
+            person_entity_ids: None,
+            zone_entity_ids: None,
+        });
+        pairing.approved = true;
+        log::info!("Paired display '{name}'");
+        Ok(tonic::Response::new(ApprovePairingResponse {}))
+    }
+}
+
+/// Approve a pairing with the exporter running on this machine.
+pub async fn approve(config: &config::PairingConfig, code: &str, name: &str) -> anyhow::Result<()> {
+    let channel =
+        tonic::transport::Channel::from_shared(format!("http://127.0.0.1:{}", config.admin_port))?
+            .connect()
+            .await?;
+    AdminServiceClient::new(channel)
+        .approve_pairing(ApprovePairingRequest {
+            code: code.to_string(),
+            display_name: name.to_string(),
+        })
+        .await
+        .map_err(|s| anyhow::anyhow!("{}", s.message()))?;
+    Ok(())
+}
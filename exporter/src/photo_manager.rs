@@ -1,9 +1,12 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::{io::Read, path::PathBuf};
 
-use crate::homeassistant_types::EntityId;
+use crate::homeassistant::{Person, TrackedEntityId};
 
 const VALID_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
 
+#[derive(Clone)]
 pub struct PhotoManager {
     photos_directory: std::path::PathBuf,
 }
@@ -12,7 +15,7 @@ impl PhotoManager {
         PhotoManager { photos_directory }
     }
 
-    fn potential_paths(&self, entity_id: &impl EntityId) -> Vec<PathBuf> {
+    fn potential_paths(&self, entity_id: &impl std::fmt::Display) -> Vec<PathBuf> {
         // Replace `.` with `_` so that setting a `.png`/`.jpg` extension is easier.
         let filename = entity_id.to_string().replace('.', "_");
         let base_name = self.photos_directory.join(filename);
@@ -21,15 +24,7 @@ impl PhotoManager {
             .to_vec()
     }
 
-    // Vulnerable to race conditions.
-    pub fn find_first_existing_path(&self, entity_id: &impl EntityId) -> Option<PathBuf> {
-        self.potential_paths(entity_id)
-            .iter()
-            .find(|p| p.exists())
-            .cloned()
-    }
-
-    pub fn get_photo(&self, entity_id: &impl EntityId) -> std::io::Result<Vec<u8>> {
+    pub fn get_photo(&self, entity_id: &impl std::fmt::Display) -> std::io::Result<Vec<u8>> {
         let Some(Ok(mut f)) = self
             .potential_paths(entity_id)
             .iter()
@@ -43,6 +38,35 @@ impl PhotoManager {
         };
         let mut buffer = vec![];
         f.read_to_end(&mut buffer)?;
-        return Ok(buffer);
+        Ok(buffer)
+    }
+}
+
+/// Each person's photo, with the `entity_picture` it was fetched from.
+type Fetched = HashMap<TrackedEntityId, (String, Arc<Vec<u8>>)>;
+
+/// Photos fetched from HA, so that each is only fetched again once the person's `entity_picture` changes.
+/// Clones share the same photos.
+#[derive(Clone, Default)]
+pub struct FetchedPhotos {
+    photos: Arc<Mutex<Fetched>>,
+}
+impl FetchedPhotos {
+    /// `None` if the person's photo hasn't been fetched, or their `entity_picture` has changed since.
+    pub fn get(&self, person: &Person) -> Option<Arc<Vec<u8>>> {
+        let entity_picture = person.get_entity_picture_path()?;
+        match self.photos.lock().unwrap().get(&person.id) {
+            Some((fetched_from, photo)) if *fetched_from == entity_picture => Some(photo.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, person: &Person, photo: Vec<u8>) {
+        if let Some(entity_picture) = person.get_entity_picture_path() {
+            self.photos
+                .lock()
+                .unwrap()
+                .insert(person.id.clone(), (entity_picture, Arc::new(photo)));
+        }
     }
 }
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use image::{DynamicImage, GenericImageView};
+use lib::clock_pb::Dimensions;
+
+const JPEG_QUALITY: u8 = 85;
+/// Displays choose the sizes they ask for, so the cache is bounded by its total size, dropping the photos that
+/// were used least recently first.
+const MAX_CACHED_BYTES: usize = 32 * 1024 * 1024;
+
+/// Keyed by the original photo's digest and the target width and height.
+type CacheKey = (String, u32, u32);
+
+/// What to send for a photo at some size.
+#[derive(Clone)]
+enum Resized {
+    Photo(Arc<Vec<u8>>),
+    /// The original is already small enough, or can't be resized. Remembered so it isn't decoded again.
+    Original,
+}
+impl Resized {
+    /// The key's counted too, so that remembering to send originals isn't free.
+    fn cost(&self, key: &CacheKey) -> usize {
+        key.0.len()
+            + match self {
+                Resized::Photo(photo) => photo.len(),
+                Resized::Original => 0,
+            }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("Resizing task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// Shrinks photos to the size a display will actually draw them at. Resized photos are cached by the
+/// digest of the original and the target size, since displays ask for the same few sizes repeatedly.
+#[derive(Clone, Default)]
+pub struct PhotoResizer {
+    cache: Arc<Mutex<ResizedPhotos>>,
+}
+
+#[derive(Default)]
+struct ResizedPhotos {
+    /// Each photo with the value of `uses` when it was last used.
+    photos: HashMap<CacheKey, (u64, Resized)>,
+    uses: u64,
+    total_bytes: usize,
+}
+impl ResizedPhotos {
+    fn get(&mut self, key: &CacheKey) -> Option<Resized> {
+        self.uses += 1;
+        let (last_used, photo) = self.photos.get_mut(key)?;
+        *last_used = self.uses;
+        Some(photo.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, photo: Resized) {
+        let cost = photo.cost(&key);
+        if cost > MAX_CACHED_BYTES {
+            return;
+        }
+        self.uses += 1;
+        self.total_bytes += cost;
+        if let Some((_, replaced)) = self.photos.insert(key.clone(), (self.uses, photo)) {
+            self.total_bytes -= replaced.cost(&key);
+        }
+        while self.total_bytes > MAX_CACHED_BYTES {
+            let Some(oldest) = self
+                .photos
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some((_, evicted)) = self.photos.remove(&oldest) {
+                self.total_bytes -= evicted.cost(&oldest);
+            }
+        }
+    }
+}
+impl PhotoResizer {
+    /// Returns the photo scaled down so that it just covers `target` (the display crops it to fit), or the
+    /// original if it's already small enough or can't be resized.
+    pub async fn resize(&self, data: Vec<u8>, target: Dimensions) -> Vec<u8> {
+        let key = (lib::photo::digest(&data), target.width, target.height);
+        match self.cache.lock().unwrap().get(&key) {
+            Some(Resized::Photo(resized)) => return resized.as_ref().clone(),
+            Some(Resized::Original) => return data,
+            None => {}
+        }
+
+        // Decoding and resizing large photos takes a while, so keep it off the async workers.
+        let original = data.clone();
+        let resized = tokio::task::spawn_blocking(move || resize_photo(&original, target))
+            .await
+            .map_err(Error::from)
+            .and_then(|result| result);
+        match resized {
+            Ok(Some(resized)) => {
+                log::info!(
+                    "Resized photo to fit {}x{}: {} bytes -> {} bytes",
+                    target.width,
+                    target.height,
+                    data.len(),
+                    resized.len()
+                );
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(key, Resized::Photo(Arc::new(resized.clone())));
+                resized
+            }
+            Ok(None) => {
+                self.cache.lock().unwrap().insert(key, Resized::Original);
+                data
+            }
+            Err(e) => {
+                log::warn!("Unable to resize photo, sending the original: {e}");
+                self.cache.lock().unwrap().insert(key, Resized::Original);
+                data
+            }
+        }
+    }
+}
+
+/// Returns `None` if the photo doesn't need shrinking.
+fn resize_photo(data: &[u8], target: Dimensions) -> Result<Option<Vec<u8>>, Error> {
+    let image = image::load_from_memory(data)?;
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 || target.width == 0 || target.height == 0 {
+        return Ok(None);
+    }
+
+    // Scale so both dimensions are at least the target's, then the display can crop without upscaling.
+    let scale = f64::max(
+        target.width as f64 / width as f64,
+        target.height as f64 / height as f64,
+    );
+    if scale >= 1.0 {
+        return Ok(None);
+    }
+    let resized = image.resize_exact(
+        (width as f64 * scale).ceil() as u32,
+        (height as f64 * scale).ceil() as u32,
+        image::imageops::FilterType::CatmullRom,
+    );
+    Ok(Some(encode(&resized)?))
+}
+
+/// JPEG is much smaller for photos, but people photos often have transparent backgrounds so need PNG.
+fn encode(image: &DynamicImage) -> Result<Vec<u8>, Error> {
+    let mut buffer = std::io::Cursor::new(vec![]);
+    if image.color().has_alpha() {
+        image.write_to(&mut buffer, image::ImageFormat::Png)?;
+    } else {
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY);
+        DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+    }
+    Ok(buffer.into_inner())
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Displays fetch photos straight after the response that references them, and keep them, so photos that haven't
+/// been in a response for this long are no longer needed.
+const UNREFERENCED_PHOTO_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Each photo with when it was last referenced in a response.
+type Photos = HashMap<String, (Instant, Arc<Vec<u8>>)>;
+
+/// Photos that have been referenced by digest in a response, so that displays can fetch them with `GetPhoto`.
+#[derive(Clone, Default)]
+pub struct PhotoStore {
+    photos: Arc<Mutex<Photos>>,
+}
+impl PhotoStore {
+    /// Store the photo, returning its digest.
+    pub fn insert(&self, data: Vec<u8>) -> String {
+        let digest = lib::photo::digest(&data);
+        let mut photos = self.photos.lock().unwrap();
+        photos.retain(|_, (referenced, _)| referenced.elapsed() < UNREFERENCED_PHOTO_LIFETIME);
+        photos
+            .entry(digest.clone())
+            .and_modify(|(referenced, _)| *referenced = Instant::now())
+            .or_insert_with(|| (Instant::now(), Arc::new(data)));
+        digest
+    }
+
+    pub fn get(&self, digest: &str) -> Option<Arc<Vec<u8>>> {
+        self.photos
+            .lock()
+            .unwrap()
+            .get(digest)
+            .map(|(_, data)| data.clone())
+    }
+}
@@ -0,0 +1,251 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use lib::env_params::watch_for_changes;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls;
+use tokio_rustls::server::TlsStream;
+use tokio_stream::wrappers::ReceiverStream;
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+
+use crate::config;
+
+/// How often to check whether the certificate or key files have changed.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Stops connections that never finish the handshake from hanging around.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid TLS config: {0}")]
+    Config(String),
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid client CA: {0}")]
+    ClientCa(#[from] rustls::server::VerifierBuilderError),
+}
+
+pub type Incoming = ReceiverStream<std::io::Result<TlsStream<tokio::net::TcpStream>>>;
+
+fn load_certified_key(config: &config::TlsConfig) -> Result<CertifiedKey, Error> {
+    let certificates =
+        lib::tls::parse_certificates(&config.certificate.value).map_err(Error::Config)?;
+    let key = PrivateKeyDer::from_pem_slice(config.key.value.unsecure())
+        .map_err(|e| Error::Config(format!("Invalid private key: {e}")))?;
+    Ok(CertifiedKey::from_der(
+        certificates,
+        key,
+        &lib::tls::crypto_provider(),
+    )?)
+}
+
+/// Serves whichever certificate was most recently loaded.
+#[derive(Debug)]
+struct ReloadingCertificate {
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+/// Accepts client certificates issued by the configured CAs or on the allowlist. Connections without a
+/// certificate are also accepted, leaving `Authenticate` to decide which requests need one.
+#[derive(Debug)]
+struct ClientCertificates {
+    issuers: Option<Arc<dyn ClientCertVerifier>>,
+    fingerprints: Vec<String>,
+    provider: Arc<CryptoProvider>,
+}
+impl ClientCertificates {
+    fn new(config: &config::ClientCertificateConfig) -> Result<Self, Error> {
+        let provider = lib::tls::crypto_provider();
+        let issuers = match &config.ca_certificates {
+            Some(pem) => {
+                let mut roots = rustls::RootCertStore::empty();
+                for certificate in lib::tls::parse_certificates(pem).map_err(Error::Config)? {
+                    roots.add(certificate)?;
+                }
+                Some(
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                        .allow_unauthenticated()
+                        .build()?,
+                )
+            }
+            None => None,
+        };
+        Ok(ClientCertificates {
+            issuers,
+            fingerprints: config.fingerprints.clone(),
+            provider,
+        })
+    }
+}
+impl ClientCertVerifier for ClientCertificates {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        match &self.issuers {
+            Some(issuers) => issuers.root_hint_subjects(),
+            None => &[],
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if self
+            .fingerprints
+            .contains(&lib::tls::fingerprint(end_entity))
+        {
+            return Ok(ClientCertVerified::assertion());
+        }
+        match &self.issuers {
+            Some(issuers) => issuers.verify_client_cert(end_entity, intermediates, now),
+            None => Err(rustls::Error::General(
+                "Client certificate isn't on the allowlist".to_string(),
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn reload_certificate(
+    resolver: &ReloadingCertificate,
+    config: &config::TlsConfig,
+) -> Result<(), String> {
+    let reloaded = config::TlsConfig {
+        certificate: config.certificate.reload()?,
+        key: config.key.reload()?,
+        client_certificates: config.client_certificates.clone(),
+    };
+    let certified_key = load_certified_key(&reloaded).map_err(|e| e.to_string())?;
+    *resolver.certified_key.write().unwrap() = Arc::new(certified_key);
+    log::info!("Loaded new TLS certificate");
+    Ok(())
+}
+
+/// Reload the certificate whenever either of its files change, e.g. when it's renewed. If the new files are broken
+/// (including when only one of them has been replaced so far), the old certificate is kept.
+async fn reload_when_changed(resolver: Arc<ReloadingCertificate>, config: config::TlsConfig) {
+    let reload = || reload_certificate(&resolver, &config);
+    tokio::join!(
+        watch_for_changes(config.certificate.clone(), RELOAD_CHECK_INTERVAL, |_| {
+            reload()
+        }),
+        watch_for_changes(config.key.clone(), RELOAD_CHECK_INTERVAL, |_| reload()),
+    );
+}
+
+/// Accept TLS connections on `addr`, for `Server::serve_with_incoming`.
+pub async fn incoming(
+    addr: std::net::SocketAddr,
+    config: &config::TlsConfig,
+) -> Result<Incoming, Error> {
+    let resolver = Arc::new(ReloadingCertificate {
+        certified_key: RwLock::new(Arc::new(load_certified_key(config)?)),
+    });
+    if config.certificate.path.is_some() || config.key.path.is_some() {
+        tokio::spawn(reload_when_changed(resolver.clone(), config.clone()));
+    }
+
+    let builder = rustls::ServerConfig::builder_with_provider(lib::tls::crypto_provider())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_certificates {
+        Some(client_certificates) => builder
+            .with_client_cert_verifier(Arc::new(ClientCertificates::new(client_certificates)?)),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(accept_connections(listener, acceptor, tx));
+    Ok(ReceiverStream::new(rx))
+}
+
+async fn accept_connections(
+    listener: tokio::net::TcpListener,
+    acceptor: tokio_rustls::TlsAcceptor,
+    tx: mpsc::Sender<std::io::Result<TlsStream<tokio::net::TcpStream>>>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tx.closed() => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // E.g. out of file descriptors, so back off rather than spinning.
+                log::warn!("Failed to accept connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        // Handshake in the background, so one slow client doesn't hold up the others.
+        let acceptor = acceptor.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = tx.send(Ok(stream)).await;
+                }
+                Ok(Err(e)) => log::info!("TLS handshake with {peer} failed: {e}"),
+                Err(_) => log::info!("TLS handshake with {peer} timed out"),
+            }
+        });
+    }
+}
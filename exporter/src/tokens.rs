@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use aws_lc_rs::hmac;
+use aws_lc_rs::rand::SecureRandom;
+use base64::Engine;
+use lib::env_params::{watch_for_changes, ReloadableParam};
+use secstr::SecStr;
+use tokio::sync::watch;
+
+use crate::config::{self, DisplayConfig};
+use crate::homeassistant;
+
+/// Tokens look like `pdt1.<claims>.<secret>`.
+const TOKEN_PREFIX: &str = "pdt1";
+/// How often to check whether the revocation list has changed.
+const REVOCATION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const BASE64: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+/// What a token grants. Signed by the exporter, so can't be changed by the display.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TokenClaims {
+    /// Random, for revoking the token.
+    pub id: String,
+    /// Used in logs.
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_unix_seconds: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub person_entity_ids: Option<Vec<homeassistant::TrackedEntityId>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone_entity_ids: Option<Vec<homeassistant::ZoneId>>,
+}
+impl TokenClaims {
+    pub fn new(name: String) -> Result<Self, String> {
+        let mut id = [0u8; 8];
+        aws_lc_rs::rand::SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| "Failed to generate a token ID.".to_string())?;
+        Ok(TokenClaims {
+            id: id.iter().map(|b| format!("{b:02x}")).collect(),
+            name,
+            expires_unix_seconds: None,
+            person_entity_ids: None,
+            zone_entity_ids: None,
+        })
+    }
+
+    /// The display that the token's holder is treated as.
+    fn display(&self) -> DisplayConfig {
+        DisplayConfig {
+            name: format!("{} (token {})", self.name, self.id),
+            password: None,
+            password_hash: None,
+            require_client_certificate: false,
+            client_certificate_sha256: None,
+            client_certificate_subject: None,
+            person_entity_ids: self.person_entity_ids.clone(),
+            zone_entity_ids: self.zone_entity_ids.clone(),
+        }
+    }
+}
+
+/// Issues and checks tokens. Clones share the same revocation list.
+#[derive(Clone)]
+pub struct Tokens {
+    signing_key: hmac::Key,
+    /// Token IDs. A channel so that streams can end as soon as their token is revoked.
+    revoked: Arc<watch::Sender<HashSet<String>>>,
+}
+impl Tokens {
+    pub fn new(config: &config::TokenConfig) -> Self {
+        Tokens {
+            signing_key: hmac::Key::new(hmac::HMAC_SHA256, config.signing_key.unsecure()),
+            revoked: Arc::new(watch::Sender::new(
+                config
+                    .revoked
+                    .as_ref()
+                    .map(|r| parse_revocation_list(&r.value))
+                    .unwrap_or_default(),
+            )),
+        }
+    }
+
+    /// The token's secret, which the display uses as its password.
+    fn secret(&self, claims: &str) -> String {
+        let signature = hmac::sign(
+            &self.signing_key,
+            format!("people-display-token-v1\n{claims}").as_bytes(),
+        );
+        BASE64.encode(signature.as_ref())
+    }
+
+    pub fn issue(&self, claims: &TokenClaims) -> Result<String, String> {
+        let claims = format!(
+            "{TOKEN_PREFIX}.{}",
+            BASE64.encode(serde_json::to_vec(claims).map_err(|e| e.to_string())?)
+        );
+        Ok(format!("{claims}.{}", self.secret(&claims)))
+    }
+
+    /// Checks that the claims from a request are still valid, returning them, the display they grant access as and
+    /// the password that the request should have been made with.
+    pub fn check(&self, claims: &str) -> tonic::Result<(TokenClaims, DisplayConfig, SecStr)> {
+        let invalid = || tonic::Status::unauthenticated("Invalid token.");
+        let encoded = claims
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|c| c.strip_prefix('.'))
+            .ok_or_else(invalid)?;
+        let decoded: TokenClaims = BASE64
+            .decode(encoded)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(invalid)?;
+        self.check_still_valid(&decoded)?;
+        let display = decoded.display();
+        Ok((decoded, display, SecStr::from(self.secret(claims))))
+    }
+
+    /// Whether the token has expired or been revoked. Rechecked while streaming, since a stream can outlive
+    /// the token.
+    pub fn check_still_valid(&self, claims: &TokenClaims) -> tonic::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| tonic::Status::internal(e.to_string()))?
+            .as_secs() as i64;
+        if claims.expires_unix_seconds.is_some_and(|e| e <= now) {
+            return Err(tonic::Status::unauthenticated("Token has expired."));
+        }
+        if self.revoked.borrow().contains(&claims.id) {
+            return Err(tonic::Status::unauthenticated("Token has been revoked."));
+        }
+        Ok(())
+    }
+
+    /// Notified whenever the revocation list is reloaded.
+    pub fn subscribe_to_revocations(&self) -> watch::Receiver<HashSet<String>> {
+        self.revoked.subscribe()
+    }
+}
+
+/// One token ID per line. Anything after a `#` is a comment, e.g. who the token was for.
+fn parse_revocation_list(list: &str) -> HashSet<String> {
+    list.lines()
+        .filter_map(|line| line.split('#').next())
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Keeps the last good list if the file can't be read.
+pub async fn reload_revocations_when_changed(tokens: Tokens, revoked: ReloadableParam<String>) {
+    watch_for_changes(revoked, REVOCATION_CHECK_INTERVAL, |list| {
+        let ids = parse_revocation_list(list);
+        log::info!(
+            "Reloaded token revocation list, {} tokens revoked",
+            ids.len()
+        );
+        tokens.revoked.send_replace(ids);
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homeassistant::EntityId;
+
+    fn tokens(revoked: &str) -> Tokens {
+        Tokens::new(&config::TokenConfig {
+            signing_key: SecStr::from("0123456789abcdef0123456789abcdef"),
+            revoked: Some(ReloadableParam {
+                value: revoked.to_string(),
+                path: None,
+            }),
+        })
+    }
+
+    /// The claims and secret parts of a token, as `lib::password::AddPassword::with_token` splits them.
+    fn split(token: &str) -> (&str, &str) {
+        token.rsplit_once('.').unwrap()
+    }
+
+    fn assert_rejected(result: tonic::Result<(TokenClaims, DisplayConfig, SecStr)>, message: &str) {
+        let status = result.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_eq!(status.message(), message);
+    }
+
+    #[test]
+    fn accepts_issued_tokens() {
+        let tokens = tokens("");
+        let claims = TokenClaims::new("Kitchen".to_string()).unwrap();
+        let token = tokens.issue(&claims).unwrap();
+        let (encoded, secret) = split(&token);
+        let (checked, display, expected_secret) = tokens.check(encoded).unwrap();
+        assert_eq!(checked.id, claims.id);
+        assert_eq!(expected_secret, SecStr::from(secret));
+        assert_eq!(display.name, format!("Kitchen (token {})", claims.id));
+    }
+
+    #[test]
+    fn changed_claims_need_a_different_secret() {
+        let tokens = tokens("");
+        let mut claims = TokenClaims::new("Kitchen".to_string()).unwrap();
+        claims.person_entity_ids = Some(vec![]);
+        let token = tokens.issue(&claims).unwrap();
+        let (_, secret) = split(&token);
+
+        // Seeing everyone instead, with the same secret.
+        claims.person_entity_ids = None;
+        let forged = format!(
+            "{TOKEN_PREFIX}.{}",
+            BASE64.encode(serde_json::to_vec(&claims).unwrap())
+        );
+        let (_, _, expected_secret) = tokens.check(&forged).unwrap();
+        assert_ne!(expected_secret, SecStr::from(secret));
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let tokens = tokens("");
+        let claims = TokenClaims::new("Kitchen".to_string()).unwrap();
+        let token = tokens.issue(&claims).unwrap();
+        let (encoded, _) = split(&token);
+        let (_, payload) = encoded.split_once('.').unwrap();
+        assert_rejected(tokens.check(&format!("pdt0.{payload}")), "Invalid token.");
+        assert_rejected(tokens.check(payload), "Invalid token.");
+        assert_rejected(
+            tokens.check(&format!("{TOKEN_PREFIX}.not base64")),
+            "Invalid token.",
+        );
+        assert_rejected(
+            tokens.check(&format!("{TOKEN_PREFIX}.{}", BASE64.encode("{}"))),
+            "Invalid token.",
+        );
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let tokens = tokens("");
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut claims = TokenClaims::new("Kitchen".to_string()).unwrap();
+        claims.expires_unix_seconds = Some(now + 60);
+        let token = tokens.issue(&claims).unwrap();
+        assert!(tokens.check(split(&token).0).is_ok());
+
+        claims.expires_unix_seconds = Some(now);
+        let token = tokens.issue(&claims).unwrap();
+        assert_rejected(tokens.check(split(&token).0), "Token has expired.");
+    }
+
+    #[test]
+    fn rejects_revoked_tokens() {
+        let claims = TokenClaims::new("Kitchen".to_string()).unwrap();
+        let other = TokenClaims::new("Hallway".to_string()).unwrap();
+        let tokens = tokens(&format!("{} # Kitchen\n", claims.id));
+        let token = tokens.issue(&claims).unwrap();
+        assert_rejected(tokens.check(split(&token).0), "Token has been revoked.");
+        let token = tokens.issue(&other).unwrap();
+        assert!(tokens.check(split(&token).0).is_ok());
+    }
+
+    #[test]
+    fn scopes_the_display_to_the_claims() {
+        let tokens = tokens("");
+        let alice = homeassistant::TrackedEntityId::new("person.alice").unwrap();
+        let bob = homeassistant::TrackedEntityId::new("person.bob").unwrap();
+        let home = homeassistant::ZoneId::new("zone.home").unwrap();
+        let work = homeassistant::ZoneId::new("zone.work").unwrap();
+
+        let mut claims = TokenClaims::new("Kitchen".to_string()).unwrap();
+        claims.person_entity_ids = Some(vec![alice.clone()]);
+        claims.zone_entity_ids = Some(vec![home.clone()]);
+        let token = tokens.issue(&claims).unwrap();
+        let (_, display, _) = tokens.check(split(&token).0).unwrap();
+        assert!(display.can_see_person(&alice));
+        assert!(!display.can_see_person(&bob));
+        assert!(display.can_see_zone(&home));
+        assert!(!display.can_see_zone(&work));
+
+        let unscoped = TokenClaims::new("Hallway".to_string()).unwrap();
+        let token = tokens.issue(&unscoped).unwrap();
+        let (_, display, _) = tokens.check(split(&token).0).unwrap();
+        assert!(display.can_see_person(&bob));
+        assert!(display.can_see_zone(&work));
+    }
+}
@@ -41,6 +41,7 @@
         nativeBuildInputs = with pkgs; [
           SDL2
           SDL2_image
+          SDL2_ttf
           protobuf
         ];
         RUST_SRC_PATH = pkgs.rustPlatform.rustLibSrc;
@@ -59,6 +60,7 @@
         LD_LIBRARY_PATH = pkgs.lib.makeLibraryPath [
           pkgs.SDL2
           pkgs.SDL2_image
+          pkgs.SDL2_ttf
         ];
       };
     };
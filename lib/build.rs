@@ -1,4 +1,8 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_prost_build::compile_protos("lib/clock.proto")?;
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_prost_build::configure()
+        // Served by the exporter's reflection service.
+        .file_descriptor_set_path(out_dir.join("clock_descriptor.bin"))
+        .compile_protos(&["lib/clock.proto"], &["lib"])?;
     Ok(())
 }
\ No newline at end of file
@@ -1 +1,9 @@
 tonic::include_proto!("clock");
+
+/// Only logged, to help explain how a peer behaves: displays decide what to use from `Feature`s, not from this.
+/// Bumped when existing messages change meaning (e.g. how requests are authenticated, or that a response may be
+/// stale), but not for additions that older peers can ignore, such as new optional fields, RPCs or features.
+pub const PROTOCOL_REVISION: u32 = 3;
+
+/// Describes the services in `clock.proto`, for gRPC reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("clock_descriptor");
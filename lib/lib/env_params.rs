@@ -11,12 +11,30 @@ impl ConfigParamFromEnv for String {
         Ok(val.into())
     }
 }
+impl ConfigParamFromEnv for bool {
+    fn parse(val: &str) -> Result<bool, String> {
+        val.parse()
+            .map_err(|e: std::str::ParseBoolError| e.to_string())
+    }
+}
 impl ConfigParamFromEnv for u16 {
     fn parse(val: &str) -> Result<u16, String> {
         val.parse()
             .map_err(|e: std::num::ParseIntError| e.to_string())
     }
 }
+impl ConfigParamFromEnv for u32 {
+    fn parse(val: &str) -> Result<u32, String> {
+        val.parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string())
+    }
+}
+impl ConfigParamFromEnv for u64 {
+    fn parse(val: &str) -> Result<u64, String> {
+        val.parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string())
+    }
+}
 impl ConfigParamFromEnv for std::path::PathBuf {
     fn parse(val: &str) -> Result<std::path::PathBuf, String> {
         Ok(val.into())
@@ -35,6 +53,20 @@ where
         val.split(',').map(ConfigParamFromEnv::parse).collect()
     }
 }
+impl ConfigParamFromEnv for tonic::codec::CompressionEncoding {
+    fn parse(val: &str) -> Result<tonic::codec::CompressionEncoding, String> {
+        match val {
+            "gzip" => Ok(tonic::codec::CompressionEncoding::Gzip),
+            "zstd" => Ok(tonic::codec::CompressionEncoding::Zstd),
+            _ => Err(format!("Unknown compression encoding: {val}")),
+        }
+    }
+}
+impl ConfigParamFromEnv for crate::password::HashedPassword {
+    fn parse(val: &str) -> Result<Self, String> {
+        crate::password::HashedPassword::parse(val.trim())
+    }
+}
 impl ConfigParamFromEnv for SecStr {
     fn parse(val: &str) -> Result<SecStr, String> {
         Ok(SecStr::from(val))
@@ -48,17 +80,104 @@ fn _get_env_variable<T: ConfigParamFromEnv>(key: &str) -> Result<Option<T>, Stri
         Err(e) => Err(e.to_string()),
     }
 }
-pub fn get_optional_env_variable<T: ConfigParamFromEnv>(key: &str) -> Result<Option<T>, String> {
-    if let Some(v) = _get_env_variable(key)? {
-        return Ok(Some(v));
-    } else if let Some(path) = _get_env_variable::<String>(&format!("{key}_FILE"))? {
-        let contents =
-            std::fs::read_to_string(&path).map_err(|e| format!("When opening {path}: {e}"))?;
-        return Ok(Some(ConfigParamFromEnv::parse(contents.trim())?));
+/// A config value that may have been read from a file (using the `_FILE` suffix), so can be re-read when the
+/// file changes.
+#[derive(Debug, Clone)]
+pub struct ReloadableParam<T> {
+    pub value: T,
+    pub path: Option<std::path::PathBuf>,
+}
+impl<T: ConfigParamFromEnv + Clone> ReloadableParam<T> {
+    /// When the file was last modified, or `None` if the value didn't come from a file.
+    pub fn modified(&self) -> Result<Option<std::time::SystemTime>, String> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let metadata =
+            std::fs::metadata(path).map_err(|e| format!("When opening {path:?}: {e}"))?;
+        Ok(Some(
+            metadata
+                .modified()
+                .map_err(|e| format!("When opening {path:?}: {e}"))?,
+        ))
+    }
+
+    /// The value with the file read again.
+    pub fn reload(&self) -> Result<Self, String> {
+        match &self.path {
+            Some(path) => Ok(ReloadableParam {
+                value: read_param_file(path)?,
+                path: Some(path.clone()),
+            }),
+            None => Ok(self.clone()),
+        }
+    }
+}
+
+/// Checks every `interval` whether the param's file has changed, and if it has, reads it again and passes the new
+/// value to `on_reload`. If the file can't be read or `on_reload` fails, the old value is kept until the file
+/// changes again. Returns straight away if the value didn't come from a file.
+pub async fn watch_for_changes<T: ConfigParamFromEnv + Clone>(
+    param: ReloadableParam<T>,
+    interval: std::time::Duration,
+    mut on_reload: impl FnMut(&T) -> Result<(), String>,
+) {
+    let Some(path) = &param.path else {
+        return;
+    };
+    let mut last_modified = param.modified().ok().flatten();
+    loop {
+        tokio::time::sleep(interval).await;
+        let modified = match param.modified() {
+            Ok(modified) => modified,
+            Err(e) => {
+                log::warn!("Unable to check for changes to {path:?}: {e}");
+                continue;
+            }
+        };
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        if let Err(e) = param
+            .reload()
+            .and_then(|reloaded| on_reload(&reloaded.value))
+        {
+            log::error!("Unable to reload {path:?}, keeping the old value: {e}");
+        }
+    }
+}
+
+fn read_param_file<T: ConfigParamFromEnv>(path: &std::path::Path) -> Result<T, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("When opening {path:?}: {e}"))?;
+    ConfigParamFromEnv::parse(contents.trim())
+}
+
+pub fn get_optional_reloadable_env_variable<T: ConfigParamFromEnv>(
+    key: &str,
+) -> Result<Option<ReloadableParam<T>>, String> {
+    if let Some(value) = _get_env_variable(key)? {
+        return Ok(Some(ReloadableParam { value, path: None }));
+    } else if let Some(path) = _get_env_variable::<std::path::PathBuf>(&format!("{key}_FILE"))? {
+        return Ok(Some(ReloadableParam {
+            value: read_param_file(&path)?,
+            path: Some(path),
+        }));
     }
     Ok(None)
 }
+pub fn get_optional_env_variable<T: ConfigParamFromEnv>(key: &str) -> Result<Option<T>, String> {
+    Ok(get_optional_reloadable_env_variable(key)?.map(|p| p.value))
+}
 
+pub fn get_reloadable_env_variable<T: ConfigParamFromEnv>(
+    key: &str,
+) -> Result<ReloadableParam<T>, String> {
+    get_optional_reloadable_env_variable(key)?
+        .ok_or(format!("Environment variable '{key}' not set."))
+}
 pub fn get_env_variable<T: ConfigParamFromEnv>(key: &str) -> Result<T, String> {
     get_optional_env_variable(key)?.ok_or(format!("Environment variable '{key}' not set."))
 }
@@ -1,3 +1,6 @@
 pub mod clock_pb;
 pub mod env_params;
-pub mod password;
\ No newline at end of file
+pub mod password;
+pub mod photo;
+pub mod tls;
+pub mod pairing;
\ No newline at end of file
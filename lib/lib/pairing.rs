@@ -0,0 +1,107 @@
+//! Pairs a new display with an exporter, so that neither side's config needs a password copying into it.
+//!
+//! The display commits to an X25519 public key by sending its hash, receives the exporter's public key, then
+//! reveals its own. Both sides then show or check a short code derived from the two keys: the display shows it
+//! on screen, and the exporter's owner types it into `exporter pair`. A machine in the middle would have
+//! swapped the keys for its own, so the codes wouldn't match, and the commitment stops it from choosing keys
+//! until they do. Once approved, both sides derive the display's password from the shared secret, so the
+//! password itself is never sent.
+
+use std::time::Duration;
+
+use aws_lc_rs::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use aws_lc_rs::{digest, hkdf};
+use secstr::SecStr;
+
+/// How long the exporter waits for a pairing to be approved.
+pub const PAIRING_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+fn sha256(parts: &[&[u8]]) -> Vec<u8> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for part in parts {
+        context.update(part);
+    }
+    context.finish().as_ref().to_vec()
+}
+
+/// Sent before the public key itself.
+pub fn commitment(public_key: &[u8]) -> Vec<u8> {
+    sha256(&[b"people-display-pairing-commitment-v1\n", public_key])
+}
+
+/// Eight digits, e.g. `1234-5678`.
+pub fn code(display_public_key: &[u8], exporter_public_key: &[u8]) -> String {
+    let hash = sha256(&[
+        b"people-display-pairing-code-v1\n",
+        display_public_key,
+        exporter_public_key,
+    ]);
+    let number = u64::from_be_bytes(hash[..8].try_into().unwrap()) % 100_000_000;
+    format!("{:04}-{:04}", number / 10_000, number % 10_000)
+}
+
+/// Accepts either `12345678` or `1234-5678`, and any spacing.
+pub fn normalise_code(code: &str) -> String {
+    let digits: String = code.chars().filter(|c| c.is_ascii_digit()).collect();
+    match digits.len() {
+        8 => format!("{}-{}", &digits[..4], &digits[4..]),
+        _ => digits,
+    }
+}
+
+/// One side of a pairing.
+pub struct KeyPair {
+    private_key: EphemeralPrivateKey,
+    pub public_key: Vec<u8>,
+}
+impl KeyPair {
+    pub fn generate() -> Result<Self, String> {
+        let private_key =
+            EphemeralPrivateKey::generate(&X25519, &aws_lc_rs::rand::SystemRandom::new())
+                .map_err(|_| "Failed to generate a pairing key.".to_string())?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| "Failed to compute a pairing key.".to_string())?
+            .as_ref()
+            .to_vec();
+        Ok(KeyPair {
+            private_key,
+            public_key,
+        })
+    }
+
+    /// The display's password, as hex so it can be written into either side's config.
+    pub fn agree(
+        self,
+        display_public_key: &[u8],
+        exporter_public_key: &[u8],
+    ) -> Result<SecStr, String> {
+        let peer_public_key = if self.public_key == display_public_key {
+            exporter_public_key
+        } else {
+            display_public_key
+        };
+        agreement::agree_ephemeral(
+            self.private_key,
+            UnparsedPublicKey::new(&X25519, peer_public_key),
+            "Invalid pairing key.".to_string(),
+            |shared_secret| {
+                let mut password = [0u8; 32];
+                hkdf::Salt::new(
+                    hkdf::HKDF_SHA256,
+                    &[display_public_key, exporter_public_key].concat(),
+                )
+                .extract(shared_secret)
+                .expand(&[b"people-display-pairing-password-v1"], hkdf::HKDF_SHA256)
+                .and_then(|okm| okm.fill(&mut password))
+                .map_err(|_| "Failed to derive a password.".to_string())?;
+                Ok(SecStr::from(
+                    password
+                        .iter()
+                        .map(|b| format!("{b:02x}"))
+                        .collect::<String>(),
+                ))
+            },
+        )
+    }
+}
@@ -1,14 +1,320 @@
+//! Authenticates displays to exporters. The display first fetches a challenge (a random nonce and the time
+//! it was issued) from the exporter's unauthenticated `AuthService`, then sends an HMAC of the challenge and
+//! an increasing counter with each request, keyed by the shared password. The password itself never leaves
+//! the display, and a captured request can't be replayed because the exporter only accepts each counter
+//! once per challenge, and only while the challenge is fresh. Challenges are signed by the exporter rather than
+//! stored, so asking for lots of them can't push out the ones that displays are using.
+//!
+//! Exporters can store an argon2 hash of the password instead of the password itself. The hash is then the
+//! HMAC key: each challenge includes the hash's parameters and salt so that displays can derive it. A leaked
+//! hash still lets someone authenticate to that exporter, but doesn't reveal a password that may be reused.
+//!
+//! Instead of a password, a display can be given a token by the exporter, `<claims>.<secret>`. It sends the
+//! claims with each request, and uses the secret as the HMAC key. The exporter can work out the secret from
+//! the claims, so doesn't need to store anything per token.
+//!
+//! Older displays send the password itself, which exporters only accept if configured to.
+//!
+//! Exporters slow down guessing by making each address wait longer after each wrong password, and locking it
+//! out entirely after too many.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, SaltString};
+use aws_lc_rs::hmac;
+use aws_lc_rs::rand::SecureRandom;
 use secstr::SecStr;
 
+use crate::clock_pb::GetChallengeResponse;
+
 const PASSWORD_METADATA_KEY: &str = "password-bin";
+const NONCE_METADATA_KEY: &str = "auth-nonce";
+const ISSUED_METADATA_KEY: &str = "auth-issued";
+const COUNTER_METADATA_KEY: &str = "auth-counter";
+const MAC_METADATA_KEY: &str = "auth-mac-bin";
+const TOKEN_METADATA_KEY: &str = "auth-token";
+
+/// How long a challenge can be used for.
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// Challenges that have been used to authenticate are remembered until they expire, to stop requests being
+/// replayed. Each display only needs one at a time, so this is plenty.
+const MAX_USED_CHALLENGES: usize = 1024;
+/// Exporters could send any number of hash parameters, each of which is slow to hash with.
+const MAX_HASH_PARAMETERS: usize = 8;
+/// Hashes must be this long, since the length isn't included in the parameters sent to displays.
+const HASH_LENGTH: usize = argon2::Params::DEFAULT_OUTPUT_LEN;
+/// Displays hash with whatever parameters the exporter sends, so limit how much work that can be to what a
+/// Raspberry Pi can manage in well under a second. Above argon2's defaults, which `hash_password` uses.
+const MAX_HASH_MEMORY_KIB: u32 = 64 * 1024;
+const MAX_HASH_ITERATIONS: u32 = 4;
+/// Addresses to remember failed attempts from, so that many addresses can't exhaust memory.
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+
+fn hmac_key(password: &SecStr) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, password.unsecure())
+}
+
+/// Hash a password for an exporter's config, in PHC format.
+pub fn hash_password(password: &SecStr) -> Result<String, String> {
+    let mut salt = [0u8; 16];
+    aws_lc_rs::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| "Failed to generate a salt.".to_string())?;
+    let salt = SaltString::encode_b64(&salt).map_err(|e| e.to_string())?;
+    argon2::Argon2::default()
+        .hash_password(password.unsecure(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn hash_params(parsed: &PasswordHash) -> Result<argon2::Params, String> {
+    let params = argon2::Params::try_from(parsed).map_err(|e| e.to_string())?;
+    if params.m_cost() > MAX_HASH_MEMORY_KIB {
+        return Err(format!(
+            "Hash needs {}KiB of memory, more than the limit of {MAX_HASH_MEMORY_KIB}KiB.",
+            params.m_cost()
+        ));
+    }
+    if params.t_cost() > MAX_HASH_ITERATIONS {
+        return Err(format!(
+            "Hash needs {} iterations, more than the limit of {MAX_HASH_ITERATIONS}.",
+            params.t_cost()
+        ));
+    }
+    Ok(params)
+}
+
+/// Hash `password` with the algorithm, parameters and salt from `parameters`, a PHC string without the hash.
+fn derive_key(password: &SecStr, parameters: &str) -> Result<SecStr, String> {
+    let parsed = PasswordHash::new(parameters).map_err(|e| e.to_string())?;
+    let salt = parsed.salt.ok_or("No salt in the hash parameters.")?;
+    let params = hash_params(&parsed)?;
+    let hash = argon2::Argon2::default()
+        .hash_password_customized(
+            password.unsecure(),
+            Some(parsed.algorithm),
+            parsed.version,
+            params,
+            salt,
+        )
+        .map_err(|e| e.to_string())?;
+    let output = hash.hash.ok_or("Hashing produced no output.")?;
+    Ok(SecStr::from(output.as_bytes().to_vec()))
+}
+
+/// An argon2 hash of a password, as produced by `hash_password`. Clones share `last_match`.
+#[derive(Debug, Clone)]
+pub struct HashedPassword {
+    /// The PHC string without the hash, sent to displays with each challenge.
+    parameters: String,
+    hash: SecStr,
+    /// The last password that matched the hash, so displays sending the password itself don't cost a hash
+    /// with every request.
+    last_match: Arc<Mutex<Option<SecStr>>>,
+}
+impl PartialEq for HashedPassword {
+    fn eq(&self, other: &Self) -> bool {
+        self.parameters == other.parameters && self.hash == other.hash
+    }
+}
+impl HashedPassword {
+    pub fn parse(phc: &str) -> Result<Self, String> {
+        let parsed = PasswordHash::new(phc).map_err(|e| format!("Invalid password hash: {e}"))?;
+        argon2::Algorithm::try_from(parsed.algorithm)
+            .map_err(|_| format!("Unsupported password hash algorithm {}.", parsed.algorithm))?;
+        parsed.salt.ok_or("Password hash has no salt.")?;
+        hash_params(&parsed).map_err(|e| format!("Invalid password hash: {e}"))?;
+        let hash = parsed.hash.ok_or("Password hash has no hash.")?;
+        if hash.len() != HASH_LENGTH {
+            return Err(format!("Password hash must be {HASH_LENGTH} bytes long."));
+        }
+        let (parameters, _) = phc.rsplit_once('$').ok_or("Invalid password hash.")?;
+        Ok(HashedPassword {
+            parameters: parameters.to_string(),
+            hash: SecStr::from(hash.as_bytes().to_vec()),
+            last_match: Arc::default(),
+        })
+    }
+}
+
+/// A password that an exporter accepts, either as-is or as a hash.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredPassword {
+    Plaintext(SecStr),
+    Hashed(HashedPassword),
+}
+impl StoredPassword {
+    fn mac_key(&self) -> &SecStr {
+        match self {
+            StoredPassword::Plaintext(password) => password,
+            StoredPassword::Hashed(hashed) => &hashed.hash,
+        }
+    }
+
+    /// Whether `received` is the password, without hashing it.
+    fn matches_cheaply(&self, received: &SecStr) -> bool {
+        match self {
+            StoredPassword::Plaintext(password) => password == received,
+            StoredPassword::Hashed(hashed) => {
+                hashed.last_match.lock().unwrap().as_ref() == Some(received)
+            }
+        }
+    }
+
+    /// Hashing is slow, and this is called from interceptors, which can't wait on another thread. Instead the
+    /// runtime is told this thread is blocked, so it can move other tasks elsewhere.
+    fn matches(&self, received: &SecStr) -> bool {
+        match self {
+            StoredPassword::Plaintext(password) => password == received,
+            StoredPassword::Hashed(hashed) => {
+                let matches = tokio::task::block_in_place(|| {
+                    derive_key(received, &hashed.parameters).is_ok_and(|key| key == hashed.hash)
+                });
+                if matches {
+                    *hashed.last_match.lock().unwrap() = Some(received.clone());
+                }
+                matches
+            }
+        }
+    }
+}
+
+/// What's covered by the HMAC for a single request.
+fn mac_message(nonce: &str, issued_unix_seconds: i64, counter: u64) -> Vec<u8> {
+    format!("people-display-auth-v1\n{nonce}\n{issued_unix_seconds}\n{counter}").into_bytes()
+}
+
+enum ClientAuth {
+    /// Requests fail until `AddPassword::set_challenge` is called.
+    NoChallenge,
+    Challenge {
+        challenge: GetChallengeResponse,
+        received_at: Instant,
+        counter: u64,
+        /// The password, then its hash with each of the challenge's hash parameters. Each request has a MAC
+        /// from each key, since the display doesn't know which the exporter has.
+        keys: Vec<SecStr>,
+    },
+    /// For exporters that predate `AuthService`.
+    Plaintext,
+    /// For displays that authenticate some other way, e.g. with a client certificate.
+    NoPassword,
+}
 
+/// Authenticates requests from the display. Clones share the same challenge.
 #[derive(Clone)]
 pub struct AddPassword {
     password: SecStr,
+    /// The claims part of a token, if `password` is its secret.
+    token_claims: Option<String>,
+    auth: Arc<Mutex<ClientAuth>>,
+    /// Hashing is slow, and exporters send the same parameters with every challenge.
+    derived_keys: Arc<Mutex<HashMap<String, SecStr>>>,
 }
 impl AddPassword {
     pub fn new(password: SecStr) -> Self {
-        AddPassword { password }
+        AddPassword {
+            password,
+            token_claims: None,
+            auth: Arc::new(Mutex::new(ClientAuth::NoChallenge)),
+            derived_keys: Arc::default(),
+        }
+    }
+
+    pub fn with_token(token: &SecStr) -> Result<Self, String> {
+        let (claims, secret) = std::str::from_utf8(token.unsecure())
+            .ok()
+            .and_then(|token| token.trim().rsplit_once('.'))
+            .ok_or("Invalid token.")?;
+        Ok(AddPassword {
+            token_claims: Some(claims.to_string()),
+            ..AddPassword::new(SecStr::from(secret))
+        })
+    }
+
+    pub fn without_password() -> Self {
+        AddPassword {
+            password: SecStr::new(vec![]),
+            token_claims: None,
+            auth: Arc::new(Mutex::new(ClientAuth::NoPassword)),
+            derived_keys: Arc::default(),
+        }
+    }
+
+    pub async fn set_challenge(&self, challenge: GetChallengeResponse) {
+        let mut keys = vec![self.password.clone()];
+        // Token secrets are never hashed.
+        if self.token_claims.is_none() {
+            keys.extend(self.hashed_keys(&challenge).await);
+        }
+        *self.auth.lock().unwrap() = ClientAuth::Challenge {
+            challenge,
+            received_at: Instant::now(),
+            counter: 0,
+            keys,
+        };
+    }
+
+    /// The password hashed with each of the challenge's hash parameters. Hashing is done on a blocking thread,
+    /// so it doesn't hold up the runtime.
+    async fn hashed_keys(&self, challenge: &GetChallengeResponse) -> Vec<SecStr> {
+        self.derived_keys
+            .lock()
+            .unwrap()
+            .retain(|parameters, _| challenge.password_hash_parameters.contains(parameters));
+        let mut keys = vec![];
+        for parameters in challenge
+            .password_hash_parameters
+            .iter()
+            .take(MAX_HASH_PARAMETERS)
+        {
+            if let Some(key) = self.derived_keys.lock().unwrap().get(parameters) {
+                keys.push(key.clone());
+                continue;
+            }
+            let password = self.password.clone();
+            let owned_parameters = parameters.clone();
+            let derived =
+                tokio::task::spawn_blocking(move || derive_key(&password, &owned_parameters))
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()));
+            match derived {
+                Ok(key) => {
+                    self.derived_keys
+                        .lock()
+                        .unwrap()
+                        .insert(parameters.clone(), key.clone());
+                    keys.push(key);
+                }
+                Err(e) => log::warn!("Ignoring password hash parameters from the exporter: {e}"),
+            }
+        }
+        keys
+    }
+
+    /// Send the password itself with each request, for exporters that don't support challenges.
+    pub fn use_plaintext(&self) {
+        *self.auth.lock().unwrap() = ClientAuth::Plaintext;
+    }
+
+    /// Whether a new challenge is needed before the next request, because there isn't one or it expires
+    /// within `margin`.
+    pub fn needs_challenge(&self, margin: Duration) -> bool {
+        match &*self.auth.lock().unwrap() {
+            ClientAuth::NoChallenge => true,
+            ClientAuth::Challenge {
+                challenge,
+                received_at,
+                ..
+            } => {
+                let lifetime = Duration::from_secs(challenge.lifetime_seconds.into());
+                received_at.elapsed() + margin >= lifetime
+            }
+            ClientAuth::Plaintext | ClientAuth::NoPassword => false,
+        }
     }
 }
 
@@ -17,39 +323,768 @@ impl tonic::service::Interceptor for AddPassword {
         &mut self,
         mut request: tonic::Request<()>,
     ) -> Result<tonic::Request<()>, tonic::Status> {
-        let mut password_metadata =
-            tonic::metadata::BinaryMetadataValue::from_bytes(self.password.unsecure());
-        password_metadata.set_sensitive(true);
-        request
-            .metadata_mut()
-            .insert_bin(PASSWORD_METADATA_KEY, password_metadata);
+        let metadata = request.metadata_mut();
+        match &mut *self.auth.lock().unwrap() {
+            ClientAuth::NoChallenge => {
+                return Err(tonic::Status::unauthenticated(
+                    "No challenge fetched from the exporter.",
+                ))
+            }
+            ClientAuth::Challenge {
+                challenge,
+                counter,
+                keys,
+                ..
+            } => {
+                *counter += 1;
+                let message =
+                    mac_message(&challenge.nonce, challenge.issued_unix_seconds, *counter);
+                let ascii = |s: String| {
+                    s.parse()
+                        .map_err(|_| tonic::Status::internal("Invalid challenge from exporter."))
+                };
+                metadata.insert(NONCE_METADATA_KEY, ascii(challenge.nonce.clone())?);
+                metadata.insert(
+                    ISSUED_METADATA_KEY,
+                    ascii(challenge.issued_unix_seconds.to_string())?,
+                );
+                metadata.insert(COUNTER_METADATA_KEY, ascii(counter.to_string())?);
+                for key in keys {
+                    let mac = hmac::sign(&hmac_key(key), &message);
+                    metadata.append_bin(
+                        MAC_METADATA_KEY,
+                        tonic::metadata::BinaryMetadataValue::from_bytes(mac.as_ref()),
+                    );
+                }
+            }
+            ClientAuth::Plaintext if self.token_claims.is_some() => {
+                return Err(tonic::Status::unauthenticated(
+                    "Tokens need an exporter that supports challenge-response authentication.",
+                ))
+            }
+            ClientAuth::Plaintext => {
+                let mut password_metadata =
+                    tonic::metadata::BinaryMetadataValue::from_bytes(self.password.unsecure());
+                password_metadata.set_sensitive(true);
+                metadata.insert_bin(PASSWORD_METADATA_KEY, password_metadata);
+            }
+            ClientAuth::NoPassword => {}
+        }
+        if let Some(claims) = &self.token_claims {
+            metadata.insert(
+                TOKEN_METADATA_KEY,
+                claims
+                    .parse()
+                    .map_err(|_| tonic::Status::internal("Invalid token."))?,
+            );
+        }
         Ok(request)
     }
 }
 
+/// The claims part of the token that the request was made with, if any. The request still needs checking
+/// against the token's secret.
+pub fn request_token<T>(request: &tonic::Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get(TOKEN_METADATA_KEY)
+        .and_then(|claims| claims.to_str().ok())
+}
+
+/// A challenge that's been used, so that requests made with it can't be replayed.
+struct UsedChallenge {
+    issued_unix_seconds: i64,
+    /// The highest counter accepted so far.
+    last_counter: u64,
+}
+#[derive(Default)]
+struct UsedChallenges {
+    by_nonce: HashMap<String, UsedChallenge>,
+    /// Challenges issued at or before this were forgotten to make room, so can't be used again.
+    forgotten_up_to: Option<i64>,
+}
+
+/// Challenges handed out by an exporter, shared between its `AuthService` and `CheckPassword`. Anyone can ask
+/// for a challenge, so they aren't stored: the nonce is signed instead, so the exporter can recognise the ones
+/// it issued. Only challenges that have been used to authenticate are remembered.
+#[derive(Clone)]
+pub struct Challenges {
+    signing_key: hmac::Key,
+    used: Arc<Mutex<UsedChallenges>>,
+    /// For displays to derive the keys for hashed passwords.
+    hash_parameters: Arc<Vec<String>>,
+}
+impl Challenges {
+    pub fn new(passwords: &[StoredPassword]) -> Result<Self, String> {
+        let mut hash_parameters = vec![];
+        for password in passwords {
+            if let StoredPassword::Hashed(hashed) = password {
+                if !hash_parameters.contains(&hashed.parameters) {
+                    hash_parameters.push(hashed.parameters.clone());
+                }
+            }
+        }
+        if hash_parameters.len() > MAX_HASH_PARAMETERS {
+            return Err(format!(
+                "Displays only hash with up to {MAX_HASH_PARAMETERS} different password hashes, but {} were given.",
+                hash_parameters.len()
+            ));
+        }
+        let signing_key =
+            hmac::Key::generate(hmac::HMAC_SHA256, &aws_lc_rs::rand::SystemRandom::new())
+                .map_err(|_| "Failed to generate a challenge signing key.".to_string())?;
+        Ok(Challenges {
+            signing_key,
+            used: Arc::default(),
+            hash_parameters: Arc::new(hash_parameters),
+        })
+    }
+
+    /// `<random>.<signature>`, both hex-encoded.
+    fn nonce(&self, random: &str, issued_unix_seconds: i64) -> String {
+        let signature = hmac::sign(
+            &self.signing_key,
+            format!("people-display-challenge-v1\n{random}\n{issued_unix_seconds}").as_bytes(),
+        );
+        let signature: String = signature
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("{random}.{signature}")
+    }
+
+    fn is_issued(&self, nonce: &str, issued_unix_seconds: i64) -> bool {
+        let Some((random, _)) = nonce.split_once('.') else {
+            return false;
+        };
+        let expected = self.nonce(random, issued_unix_seconds);
+        aws_lc_rs::constant_time::verify_slices_are_equal(expected.as_bytes(), nonce.as_bytes())
+            .is_ok()
+    }
+
+    pub fn issue(&self) -> Result<GetChallengeResponse, tonic::Status> {
+        let mut random = [0u8; 16];
+        aws_lc_rs::rand::SystemRandom::new()
+            .fill(&mut random)
+            .map_err(|_| tonic::Status::internal("Failed to generate a nonce."))?;
+        let random: String = random.iter().map(|b| format!("{b:02x}")).collect();
+        let issued_unix_seconds = unix_seconds_now()?;
+        Ok(GetChallengeResponse {
+            nonce: self.nonce(&random, issued_unix_seconds),
+            issued_unix_seconds,
+            lifetime_seconds: CHALLENGE_LIFETIME.as_secs() as u32,
+            password_hash_parameters: self.hash_parameters.to_vec(),
+        })
+    }
+
+    /// Returns the index of the password that one of the request's MACs was made with.
+    fn verify(
+        &self,
+        passwords: &[StoredPassword],
+        request: &tonic::Request<()>,
+    ) -> Result<usize, tonic::Status> {
+        let metadata = request.metadata();
+        let get_ascii = |key: &str| {
+            metadata
+                .get(key)
+                .ok_or(tonic::Status::unauthenticated(format!(
+                    "No {key} provided."
+                )))?
+                .to_str()
+                .map_err(|e| tonic::Status::invalid_argument(format!("Invalid {key}: {e}")))
+        };
+        let nonce = get_ascii(NONCE_METADATA_KEY)?;
+        let issued_unix_seconds: i64 = get_ascii(ISSUED_METADATA_KEY)?
+            .parse()
+            .map_err(|e| tonic::Status::invalid_argument(format!("Invalid timestamp: {e}")))?;
+        let counter: u64 = get_ascii(COUNTER_METADATA_KEY)?
+            .parse()
+            .map_err(|e| tonic::Status::invalid_argument(format!("Invalid counter: {e}")))?;
+        let macs = metadata
+            .get_all_bin(MAC_METADATA_KEY)
+            .iter()
+            .take(MAX_HASH_PARAMETERS + 1)
+            .map(|mac| mac.to_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| tonic::Status::invalid_argument(format!("Invalid MAC: {e}")))?;
+
+        let unknown = || tonic::Status::unauthenticated("Unknown or expired challenge.");
+        let now = unix_seconds_now()?;
+        let lifetime = CHALLENGE_LIFETIME.as_secs() as i64;
+        if !(0..lifetime).contains(&(now - issued_unix_seconds))
+            || !self.is_issued(nonce, issued_unix_seconds)
+        {
+            return Err(unknown());
+        }
+        let message = mac_message(nonce, issued_unix_seconds, counter);
+        let index = passwords
+            .iter()
+            .position(|password| {
+                let key = hmac_key(password.mac_key());
+                macs.iter()
+                    .any(|mac| hmac::verify(&key, &message, mac).is_ok())
+            })
+            .ok_or(tonic::Status::unauthenticated("Password doesn't match."))?;
+
+        let mut used = self.used.lock().unwrap();
+        used.by_nonce
+            .retain(|_, c| now - c.issued_unix_seconds < lifetime);
+        if let Some(challenge) = used.by_nonce.get_mut(nonce) {
+            if counter <= challenge.last_counter {
+                return Err(tonic::Status::unauthenticated("Request has been replayed."));
+            }
+            challenge.last_counter = counter;
+            return Ok(index);
+        }
+        if used
+            .forgotten_up_to
+            .is_some_and(|forgotten| issued_unix_seconds <= forgotten)
+        {
+            return Err(unknown());
+        }
+        if used.by_nonce.len() >= MAX_USED_CHALLENGES {
+            let oldest = used
+                .by_nonce
+                .iter()
+                .min_by_key(|(_, c)| c.issued_unix_seconds)
+                .map(|(nonce, c)| (nonce.clone(), c.issued_unix_seconds));
+            if let Some((oldest, issued)) = oldest {
+                used.by_nonce.remove(&oldest);
+                used.forgotten_up_to = used.forgotten_up_to.max(Some(issued));
+            }
+        }
+        used.by_nonce.insert(
+            nonce.to_string(),
+            UsedChallenge {
+                issued_unix_seconds,
+                last_counter: counter,
+            },
+        );
+        Ok(index)
+    }
+}
+
+fn unix_seconds_now() -> Result<i64, tonic::Status> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| tonic::Status::internal(e.to_string()))?
+        .as_secs() as i64)
+}
+
+/// How quickly `CheckPassword` gives up on an address that keeps getting the password wrong.
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    /// How long an address has to wait after its first failure, doubling with each further failure.
+    pub backoff: Duration,
+    /// Failures before an address is locked out, each within `lockout` of the last. Successes in between don't
+    /// reset the count, or any valid credential could be used to keep guessing another.
+    pub max_failures: u32,
+    /// How long a lockout lasts. Failures are also forgotten after this long without another.
+    pub lockout: Duration,
+}
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            backoff: Duration::from_secs(1),
+            max_failures: 5,
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+struct FailedAttempts {
+    /// Since the last lockout.
+    count: u32,
+    last_failure: Instant,
+    /// Requests are rejected without checking the password until then.
+    blocked_until: Instant,
+}
+
+/// Failed attempts by remote address, shared between clones of `CheckPassword`.
+#[derive(Clone)]
+struct Lockouts {
+    policy: LockoutPolicy,
+    failures: Arc<Mutex<HashMap<IpAddr, FailedAttempts>>>,
+}
+impl Lockouts {
+    fn check_allowed(&self, address: IpAddr, now: Instant) -> Result<(), tonic::Status> {
+        match self.failures.lock().unwrap().get(&address) {
+            Some(attempts) if attempts.blocked_until > now => {
+                Err(tonic::Status::resource_exhausted(format!(
+                    "Too many failed attempts, try again in {}s.",
+                    (attempts.blocked_until - now).as_secs() + 1
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record_failure(&self, address: IpAddr, status: &tonic::Status, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, a| {
+            a.blocked_until > now || now.duration_since(a.last_failure) < self.policy.lockout
+        });
+        if failures.len() >= MAX_TRACKED_ADDRESSES && !failures.contains_key(&address) {
+            let oldest = failures
+                .iter()
+                .min_by_key(|(_, a)| a.last_failure)
+                .map(|(address, _)| *address);
+            if let Some(oldest) = oldest {
+                failures.remove(&oldest);
+            }
+        }
+
+        let attempts = failures.entry(address).or_insert(FailedAttempts {
+            count: 0,
+            last_failure: now,
+            blocked_until: now,
+        });
+        attempts.count += 1;
+        attempts.last_failure = now;
+        if attempts.count >= self.policy.max_failures {
+            log::warn!(
+                "Failed authentication from {address}, locked out for {}s after {} failures: {}",
+                self.policy.lockout.as_secs(),
+                attempts.count,
+                status.message()
+            );
+            attempts.count = 0;
+            attempts.blocked_until = now + self.policy.lockout;
+        } else {
+            let backoff = self
+                .policy
+                .backoff
+                .saturating_mul(1 << (attempts.count - 1).min(31))
+                .min(self.policy.lockout);
+            log::warn!(
+                "Failed authentication from {address} ({} of {} before lockout), backing off for {}ms: {}",
+                attempts.count,
+                self.policy.max_failures,
+                backoff.as_millis(),
+                status.message()
+            );
+            attempts.blocked_until = now + backoff;
+        }
+    }
+}
+
+/// Accepts requests made with any of several passwords, e.g. one per display.
 #[derive(Clone)]
 pub struct CheckPassword {
-    password: SecStr,
+    passwords: Vec<StoredPassword>,
+    challenges: Challenges,
+    /// Whether to accept the password itself from displays that predate challenges.
+    allow_plaintext: bool,
+    lockouts: Lockouts,
 }
 impl CheckPassword {
-    pub fn new(password: SecStr) -> Self {
-        CheckPassword { password }
+    pub fn new(
+        passwords: Vec<StoredPassword>,
+        challenges: Challenges,
+        allow_plaintext: bool,
+        lockout_policy: LockoutPolicy,
+    ) -> Self {
+        CheckPassword {
+            passwords,
+            challenges,
+            allow_plaintext,
+            lockouts: Lockouts {
+                policy: lockout_policy,
+                failures: Arc::default(),
+            },
+        }
     }
-}
 
-impl tonic::service::Interceptor for CheckPassword {
-    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+    /// Accept different passwords, keeping the same challenges and record of failed attempts.
+    pub fn with_passwords(&self, passwords: Vec<StoredPassword>) -> Self {
+        CheckPassword {
+            passwords,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the index of the password that the request was made with, or `None` if it didn't include
+    /// one. Addresses that keep failing are rejected without checking.
+    pub fn check(&self, request: &tonic::Request<()>) -> Result<Option<usize>, tonic::Status> {
+        let metadata = request.metadata();
+        if !metadata.contains_key(MAC_METADATA_KEY) && !metadata.contains_key(PASSWORD_METADATA_KEY)
+        {
+            return Ok(None);
+        }
+        // Without an address (e.g. over a unix socket) there's nothing to track attempts by.
+        let address = request.remote_addr().map(|a| a.ip());
+        if let Some(address) = address {
+            self.lockouts.check_allowed(address, Instant::now())?;
+        }
+
+        let result = if metadata.contains_key(MAC_METADATA_KEY) {
+            self.challenges.verify(&self.passwords, request)
+        } else {
+            self.check_plaintext(request)
+        };
+        if let Err(status) = &result {
+            self.record_failure(request, status);
+        }
+        result.map(Some)
+    }
+
+    /// Rejects addresses that keep failing, for requests that are authenticated some other way than `check`.
+    pub fn check_allowed(&self, request: &tonic::Request<()>) -> Result<(), tonic::Status> {
+        match request.remote_addr() {
+            Some(address) => self.lockouts.check_allowed(address.ip(), Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts towards locking out the request's address, for failures found other than by `check`.
+    pub fn record_failure(&self, request: &tonic::Request<()>, status: &tonic::Status) {
+        match request.remote_addr() {
+            Some(address) => self
+                .lockouts
+                .record_failure(address.ip(), status, Instant::now()),
+            None => log::warn!(
+                "Failed authentication from an unknown address: {}",
+                status.message()
+            ),
+        }
+    }
+
+    fn check_plaintext(&self, request: &tonic::Request<()>) -> Result<usize, tonic::Status> {
         let metadata = request
             .metadata()
             .get_bin(PASSWORD_METADATA_KEY)
             .ok_or(tonic::Status::unauthenticated("No password provided."))?;
+        if !self.allow_plaintext {
+            return Err(tonic::Status::unauthenticated(
+                "Plaintext passwords aren't accepted, the display needs upgrading.",
+            ));
+        }
         let received_password_bytes = metadata.to_bytes().map_err(|e| {
             tonic::Status::invalid_argument(format!("Invalid password provided: {e}"))
         })?;
 
-        if secstr::SecStr::new(received_password_bytes.to_vec()) != self.password {
-            return Err(tonic::Status::unauthenticated("Password doesn't match."));
+        let received_password = secstr::SecStr::new(received_password_bytes.to_vec());
+        // Checking every password cheaply first means a display's password is only hashed against the other
+        // displays' hashes the first time it's used.
+        self.passwords
+            .iter()
+            .position(|password| password.matches_cheaply(&received_password))
+            .or_else(|| {
+                self.passwords
+                    .iter()
+                    .position(|password| password.matches(&received_password))
+            })
+            .ok_or(tonic::Status::unauthenticated("Password doesn't match."))
+    }
+}
+
+impl tonic::service::Interceptor for CheckPassword {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        match self.check(&request)? {
+            Some(_) => Ok(request),
+            None => Err(tonic::Status::unauthenticated("No password provided.")),
         }
-        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address() -> IpAddr {
+        IpAddr::from([192, 0, 2, 1])
+    }
+
+    fn plaintext_request(password: &str) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        request.metadata_mut().insert_bin(
+            PASSWORD_METADATA_KEY,
+            tonic::metadata::BinaryMetadataValue::from_bytes(password.as_bytes()),
+        );
+        request
+            .extensions_mut()
+            .insert(tonic::transport::server::TcpConnectInfo {
+                local_addr: None,
+                remote_addr: Some((address(), 1234).into()),
+            });
+        request
+    }
+
+    fn lockouts() -> Lockouts {
+        Lockouts {
+            policy: LockoutPolicy {
+                backoff: Duration::from_secs(1),
+                max_failures: 3,
+                lockout: Duration::from_secs(60),
+            },
+            failures: Arc::default(),
+        }
+    }
+
+    fn fail(lockouts: &Lockouts, address: IpAddr, now: Instant) {
+        lockouts.record_failure(
+            address,
+            &tonic::Status::unauthenticated("Password doesn't match."),
+            now,
+        );
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn backs_off_after_each_failure() {
+        let lockouts = lockouts();
+        let start = Instant::now();
+        fail(&lockouts, address(), start);
+        assert!(lockouts.check_allowed(address(), start).is_err());
+        assert!(lockouts.check_allowed(address(), start + secs(1)).is_ok());
+
+        fail(&lockouts, address(), start + secs(1));
+        assert!(lockouts.check_allowed(address(), start + secs(2)).is_err());
+        assert!(lockouts.check_allowed(address(), start + secs(3)).is_ok());
+    }
+
+    #[test]
+    fn locks_out_after_max_failures() {
+        let lockouts = lockouts();
+        let start = Instant::now();
+        for i in 0..3 {
+            fail(&lockouts, address(), start + secs(10 * i));
+        }
+        let locked_out = start + secs(20);
+        let status = lockouts.check_allowed(address(), locked_out).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(lockouts
+            .check_allowed(address(), locked_out + secs(59))
+            .is_err());
+        assert!(lockouts
+            .check_allowed(address(), locked_out + secs(60))
+            .is_ok());
+
+        // The count starts again after a lockout.
+        fail(&lockouts, address(), locked_out + secs(60));
+        assert!(lockouts
+            .check_allowed(address(), locked_out + secs(61))
+            .is_ok());
+    }
+
+    #[test]
+    fn forgets_failures_after_lockout_duration() {
+        let lockouts = lockouts();
+        let start = Instant::now();
+        fail(&lockouts, address(), start);
+        fail(&lockouts, address(), start + secs(10));
+        // Long enough after the last failure that it's forgotten, so this is the first again.
+        fail(&lockouts, address(), start + secs(70));
+        assert!(lockouts.check_allowed(address(), start + secs(71)).is_ok());
+        fail(&lockouts, address(), start + secs(71));
+        assert!(lockouts.check_allowed(address(), start + secs(73)).is_ok());
+    }
+
+    #[test]
+    fn tracks_addresses_separately() {
+        let lockouts = lockouts();
+        let other = IpAddr::from([192, 0, 2, 2]);
+        let start = Instant::now();
+        for i in 0..3 {
+            fail(&lockouts, address(), start + secs(10 * i));
+        }
+        assert!(lockouts.check_allowed(address(), start + secs(20)).is_err());
+        assert!(lockouts.check_allowed(other, start + secs(20)).is_ok());
+
+        fail(&lockouts, other, start + secs(20));
+        assert!(lockouts.check_allowed(other, start + secs(21)).is_ok());
+    }
+
+    #[test]
+    fn successes_dont_reset_failures() {
+        let check_password = CheckPassword::new(
+            vec![StoredPassword::Plaintext(SecStr::from("right"))],
+            Challenges::new(&[]).unwrap(),
+            true,
+            LockoutPolicy {
+                backoff: Duration::ZERO,
+                max_failures: 3,
+                lockout: Duration::from_secs(60),
+            },
+        );
+        for _ in 0..3 {
+            assert!(check_password.check(&plaintext_request("right")).is_ok());
+            let status = check_password
+                .check(&plaintext_request("wrong"))
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+        let status = check_password
+            .check(&plaintext_request("right"))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    fn plaintext(password: &str) -> StoredPassword {
+        StoredPassword::Plaintext(SecStr::from(password))
+    }
+
+    /// A display's credentials with a challenge from `challenges`.
+    fn display(password: &str, challenge: GetChallengeResponse) -> AddPassword {
+        let display = AddPassword::new(SecStr::from(password));
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(display.set_challenge(challenge));
+        display
+    }
+
+    fn signed_request(display: &mut AddPassword) -> tonic::Request<()> {
+        use tonic::service::Interceptor;
+        display.call(tonic::Request::new(())).unwrap()
+    }
+
+    fn assert_rejected(result: Result<usize, tonic::Status>, message: &str) {
+        let status = result.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_eq!(status.message(), message);
+    }
+
+    #[test]
+    fn verify_accepts_each_password() {
+        let passwords = [plaintext("kitchen"), plaintext("hallway")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut kitchen = display("kitchen", challenges.issue().unwrap());
+        let mut hallway = display("hallway", challenges.issue().unwrap());
+        assert_eq!(
+            challenges
+                .verify(&passwords, &signed_request(&mut kitchen))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            challenges
+                .verify(&passwords, &signed_request(&mut hallway))
+                .unwrap(),
+            1
+        );
+        // Later requests with the same challenge are fine too.
+        assert_eq!(
+            challenges
+                .verify(&passwords, &signed_request(&mut kitchen))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn verify_accepts_hashed_passwords() {
+        let hash = hash_password(&SecStr::from("kitchen")).unwrap();
+        let passwords = [StoredPassword::Hashed(
+            HashedPassword::parse(&hash).unwrap(),
+        )];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut kitchen = display("kitchen", challenges.issue().unwrap());
+        assert_eq!(
+            challenges
+                .verify(&passwords, &signed_request(&mut kitchen))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn verify_rejects_wrong_password() {
+        let passwords = [plaintext("kitchen")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut display = display("hallway", challenges.issue().unwrap());
+        assert_rejected(
+            challenges.verify(&passwords, &signed_request(&mut display)),
+            "Password doesn't match.",
+        );
+    }
+
+    #[test]
+    fn verify_rejects_replayed_requests() {
+        let passwords = [plaintext("kitchen")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut display = display("kitchen", challenges.issue().unwrap());
+        let first = signed_request(&mut display);
+        let second = signed_request(&mut display);
+        challenges.verify(&passwords, &first).unwrap();
+        assert_rejected(
+            challenges.verify(&passwords, &first),
+            "Request has been replayed.",
+        );
+        challenges.verify(&passwords, &second).unwrap();
+    }
+
+    #[test]
+    fn verify_rejects_reused_counters() {
+        let passwords = [plaintext("kitchen")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut display = display("kitchen", challenges.issue().unwrap());
+        let first = signed_request(&mut display);
+        let second = signed_request(&mut display);
+        challenges.verify(&passwords, &second).unwrap();
+        assert_rejected(
+            challenges.verify(&passwords, &first),
+            "Request has been replayed.",
+        );
+    }
+
+    #[test]
+    fn verify_rejects_changed_counters() {
+        let passwords = [plaintext("kitchen")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut display = display("kitchen", challenges.issue().unwrap());
+        let mut request = signed_request(&mut display);
+        request
+            .metadata_mut()
+            .insert(COUNTER_METADATA_KEY, "100".parse().unwrap());
+        assert_rejected(
+            challenges.verify(&passwords, &request),
+            "Password doesn't match.",
+        );
+    }
+
+    #[test]
+    fn verify_rejects_expired_challenges() {
+        let passwords = [plaintext("kitchen")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut challenge = challenges.issue().unwrap();
+        challenge.issued_unix_seconds -= CHALLENGE_LIFETIME.as_secs() as i64;
+        let (random, _) = challenge.nonce.split_once('.').unwrap();
+        challenge.nonce = challenges.nonce(random, challenge.issued_unix_seconds);
+        let mut display = display("kitchen", challenge);
+        assert_rejected(
+            challenges.verify(&passwords, &signed_request(&mut display)),
+            "Unknown or expired challenge.",
+        );
+    }
+
+    #[test]
+    fn verify_rejects_challenges_from_elsewhere() {
+        let passwords = [plaintext("kitchen")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let other_exporter = Challenges::new(&passwords).unwrap();
+        let mut display = display("kitchen", other_exporter.issue().unwrap());
+        assert_rejected(
+            challenges.verify(&passwords, &signed_request(&mut display)),
+            "Unknown or expired challenge.",
+        );
+    }
+
+    #[test]
+    fn verify_rejects_changed_issue_times() {
+        let passwords = [plaintext("kitchen")];
+        let challenges = Challenges::new(&passwords).unwrap();
+        let mut challenge = challenges.issue().unwrap();
+        // Would make the challenge last longer, if the nonce's signature didn't cover it.
+        challenge.issued_unix_seconds += 1;
+        let mut display = display("kitchen", challenge);
+        assert_rejected(
+            challenges.verify(&passwords, &signed_request(&mut display)),
+            "Unknown or expired challenge.",
+        );
     }
 }
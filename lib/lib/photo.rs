@@ -0,0 +1,16 @@
+/// A content-addressed identifier for a photo: the lowercase hex SHA-256 of its contents.
+pub fn digest(data: &[u8]) -> String {
+    aws_lc_rs::digest::digest(&aws_lc_rs::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Whether `digest` looks like something produced by `digest`. Useful before e.g. using it as a filename.
+pub fn is_valid_digest(digest: &str) -> bool {
+    digest.len() == 64
+        && digest
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
@@ -0,0 +1,168 @@
+//! TLS helpers for when tonic's own TLS config isn't flexible enough.
+
+use std::sync::Arc;
+
+use tokio_rustls::rustls;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+}
+
+/// Parse every certificate in a PEM file.
+pub fn parse_certificates(pem: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = CertificateDer::pem_slice_iter(pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate: {e}"))?;
+    if certificates.is_empty() {
+        return Err("No certificates found".to_string());
+    }
+    Ok(certificates)
+}
+
+/// The lowercase hex SHA-256 of a certificate, which is what
+/// `openssl x509 -noout -fingerprint -sha256` prints (minus the colons and case).
+pub fn fingerprint(certificate: &CertificateDer<'_>) -> String {
+    crate::photo::digest(certificate)
+}
+
+/// Normalise a fingerprint copied from e.g. `openssl` into the form `fingerprint` produces.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<String, String> {
+    let normalised = fingerprint.replace(':', "").to_ascii_lowercase();
+    if !crate::photo::is_valid_digest(&normalised) {
+        return Err(format!("Invalid SHA-256 fingerprint: {fingerprint}"));
+    }
+    Ok(normalised)
+}
+
+/// Trusts exactly one certificate, regardless of who issued it or which names it's valid for.
+#[derive(Debug)]
+pub struct PinnedCertificate {
+    /// As produced by `fingerprint`.
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+impl PinnedCertificate {
+    pub fn new(certificate: &CertificateDer<'_>) -> Self {
+        Self::from_fingerprint(fingerprint(certificate))
+    }
+
+    /// `fingerprint` must be normalised by `parse_fingerprint`.
+    pub fn from_fingerprint(fingerprint: String) -> Self {
+        PinnedCertificate {
+            fingerprint,
+            provider: crypto_provider(),
+        }
+    }
+}
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) != self.fingerprint {
+            return Err(rustls::Error::General(
+                "Certificate doesn't match the pinned certificate".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// The URI's host, without the brackets around IPv6 addresses (e.g. `::1` rather than `[::1]`), to connect to
+/// or check the certificate against.
+pub fn uri_host(uri: &tonic::transport::Uri) -> Option<&str> {
+    uri.host()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+/// Connect to `endpoint` over TLS using `config`, presenting `server_name` for SNI and certificate checks.
+/// `endpoint` must use the `http` scheme, since tonic refuses to connect to `https` URIs without its own TLS
+/// config.
+pub async fn connect(
+    endpoint: tonic::transport::Endpoint,
+    mut config: rustls::ClientConfig,
+    server_name: ServerName<'static>,
+) -> Result<tonic::transport::Channel, tonic::transport::Error> {
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    endpoint
+        .connect_with_connector(tower::service_fn(move |uri: tonic::transport::Uri| {
+            let connector = connector.clone();
+            let server_name = server_name.clone();
+            async move {
+                let host = uri_host(&uri).ok_or(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "URI has no host",
+                ))?;
+                let port = uri.port_u16().unwrap_or(443);
+                let stream = tokio::net::TcpStream::connect((host, port)).await?;
+                let stream = connector.connect(server_name, stream).await?;
+                Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(stream))
+            }
+        }))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri_host_strips_ipv6_brackets() {
+        let host = |uri: &str| uri_host(&uri.parse().unwrap()).map(str::to_string);
+        assert_eq!(host("https://[::1]:12345").as_deref(), Some("::1"));
+        assert_eq!(
+            host("https://192.0.2.1:12345").as_deref(),
+            Some("192.0.2.1")
+        );
+        assert_eq!(
+            host("https://exporter.home").as_deref(),
+            Some("exporter.home")
+        );
+        assert!(ServerName::try_from(host("https://[::1]:12345").unwrap()).is_ok());
+    }
+}